@@ -1,7 +1,7 @@
 #[macro_use]
 extern crate rocket;
 
-use newrelic::{Datastore, ExternalParamsBuilder};
+use newrelic::Datastore;
 use rocket::serde::json::Json;
 use rocket_newrelic::{NewRelic, Transaction};
 use serde_json::Value;
@@ -32,27 +32,20 @@ async fn create_user(transaction: &Transaction, user: Json<Value>) {
         Err(_) => println!("Could not create user"),
     }
 
-    // Doing expensive operations in a custom segment
-    let _expensive_value: Result<reqwest::Response, reqwest::Error> = transaction
-        .custom_segment("process user", "process", |s| {
-            // Nesting an external segment within the custom segment
-            let url = "https://logging-thing";
-            let external_params = ExternalParamsBuilder::new(url)
-                .procedure("set")
-                .library("reqwest")
-                .build()
-                .unwrap();
-            s.external_nested(&external_params, |_| {
-                reqwest::Client::new().post(url).send()
-            })
+    // Awaiting an external request in an external segment, which is kept
+    // open until the request has completed
+    let url = "https://logging-thing";
+    let _response: Result<reqwest::Response, reqwest::Error> = transaction
+        .external_segment_async(url, Some("set"), Some("reqwest"), || {
+            reqwest::Client::new().post(url).send()
         })
         .await;
 }
 
 #[launch]
 fn launch() -> _ {
-    let newrelic = NewRelic::from_env();
+    let newrelic = NewRelic::from_env().expect("Could not register with New Relic");
     rocket::build()
-        .manage(newrelic)
+        .attach(newrelic)
         .mount("/", routes![create_user])
 }
@@ -53,13 +53,53 @@ fn launch() -> _ {
     let newrelic = NewRelic::new("MY_APP_NAME", "MY_LICENSE_KEY")
         .expect("Could not register with New Relic");
     rocket::build()
-        .manage(newrelic)
+        .attach(newrelic)
         .mount("/root", rocket::routes![get_me])
 }
 ```
 
 In the above example we'd then be able to see these transactions under
-`/root/get_me`.
+`root/get_me`.
+
+Repeated, leading and trailing slashes are removed from transaction names, so
+routes mounted at `/` are named after just their handler, and routes without
+a name (such as those created manually from a handler) are named
+`unknown_handler`:
+
+```rust
+use rocket::{
+    http::Method,
+    local::blocking::Client,
+    route::{BoxFuture, Outcome},
+    Data, Request, Route,
+};
+use rocket_newrelic::{NewRelic, Transaction};
+
+#[rocket::get("/me")]
+fn get_me(_transaction: &Transaction) {}
+
+fn unnamed_handler<'r>(request: &'r Request<'_>, _: Data<'r>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        let _ = request.guard::<&Transaction>().await;
+        Outcome::from(request, ())
+    })
+}
+let unnamed = Route::new(Method::Get, "/unnamed", unnamed_handler);
+
+let (newrelic, recorder) = NewRelic::mock();
+let rocket = rocket::build()
+    .attach(newrelic)
+    .mount("/", rocket::routes![get_me])
+    .mount("/api//v1/", rocket::routes![get_me])
+    .mount("/", vec![unnamed]);
+let client = Client::tracked(rocket).expect("valid rocket");
+client.get("/me").dispatch();
+client.get("/api/v1/me").dispatch();
+client.get("/unnamed").dispatch();
+
+let names: Vec<_> = recorder.transactions().into_iter().map(|t| t.name).collect();
+assert_eq!(names, ["get_me", "api/v1/get_me", "unknown_handler"]);
+```
 
 ## Advanced usage
 
@@ -69,7 +109,7 @@ segments. The below example demonstrates some of this functionality; see the
 methods of `Transaction` for more details.
 
 ```rust
-use newrelic::{Datastore, ExternalParamsBuilder};
+use newrelic::Datastore;
 use rocket::serde::json::Json;
 use rocket_newrelic::{NewRelic, Transaction};
 use serde_json::Value;
@@ -100,27 +140,21 @@ async fn create_user(transaction: &Transaction, user: Json<Value>) {
         Err(_) => println!("Could not create user"),
     }
 
-    // Doing expensive operations in a custom segment
-    let _expensive_value: Result<reqwest::Response, reqwest::Error> =
-        transaction.custom_segment("process user", "process", |s| {
-            // Nesting an external segment within the custom segment
-            let url = "https://logging-thing";
-            let external_params = ExternalParamsBuilder::new(url)
-                .procedure("set")
-                .library("reqwest")
-                .build()
-                .unwrap();
-            s.external_nested(&external_params, |_| {
-                reqwest::Client::new().post(url).send()
-            })
-        }).await;
+    // Awaiting an external request in an external segment, which is kept
+    // open until the request has completed
+    let url = "https://logging-thing";
+    let _response: Result<reqwest::Response, reqwest::Error> = transaction
+        .external_segment_async(url, Some("set"), Some("reqwest"), || {
+            reqwest::Client::new().post(url).send()
+        })
+        .await;
 }
 
 #[rocket::launch]
 fn launch() -> _ {
-    let newrelic = NewRelic::from_env();
+    let newrelic = NewRelic::from_env().expect("Could not register with New Relic");
     rocket::build()
-        .manage(newrelic)
+        .attach(newrelic)
         .mount("/", rocket::routes![create_user])
 }
 ```
@@ -130,39 +164,191 @@ fn launch() -> _ {
 With the `diesel` feature enabled it's possible to pass a Diesel query,
 along with a `&Connection`, into the `diesel_segment_load` and
 `diesel_segment_first` methods of a [`Transaction`]. This will log the SQL query
-and return either all results, or the first result, respectively.
+and return either all results, or the first result, respectively. To execute
+the query some other way, such as loading a limited number of rows, pass it to
+`diesel_segment` along with a closure which runs it. From async
+handlers, `diesel_segment_load_blocking` can be used instead to run the query
+on Tokio's blocking thread pool.
+
+The `diesel_mysql`, `diesel_postgres` and `diesel_sqlite` features additionally
+enable the corresponding Diesel backend, and the `diesel_load` and
+`diesel_first` methods, which infer the New Relic datastore from the
+connection rather than requiring it to be passed explicitly.
+
+With the `diesel-async` feature enabled, a query can instead be run on a
+`diesel-async` connection using the `diesel_segment_load_async` method, which
+keeps the datastore segment open until the query has completed. Note that
+`diesel-async` is built on Diesel 2, rather than the Diesel 1 used by the
+other methods.
+
+### SQLx queries
+
+With the `sqlx` feature enabled, an SQLx query can be passed into the
+`sqlx_segment` method of a [`Transaction`], along with an async function
+which executes it (e.g. using `fetch_all`). The query's SQL is recorded in
+a datastore segment which lasts until the query has completed.
+
+### Serde
+
+With the `serde` feature enabled, the top-level fields of any `Serialize`
+struct can be added as attributes at once using the `add_serialized` method
+of a [`Transaction`].
+
+### Distributed tracing
+
+With the `distributed_tracing` feature enabled, distributed tracing can be
+turned on for the New Relic app using the `distributed_tracing` method of
+[`NewRelicBuilder`]. The `distributed_trace_headers` function can then be used
+to propagate the trace to external services called inside an external segment.
+
+### Reqwest
+
+With the `reqwest` feature enabled, a `reqwest` request can be executed in an
+external segment using the `reqwest_segment` method of a [`Transaction`], which
+takes the host from the request's URL. With `distributed_tracing` also enabled,
+the distributed trace headers are added to the request.
+
+### Tracing
+
+With the `tracing` feature enabled, transactions begun inside a [tracing]
+span record the span's id in a `trace.span_id` attribute, so New Relic
+transactions can be correlated with logs from the same span. The New Relic SDK
+doesn't expose its trace ids, so they can't be recorded in the span in turn.
+
+### Testing
+
+[`NewRelic::mock`] creates a fairing which doesn't require the New Relic SDK or
+daemon. Instead, transactions (including their names, attributes, segments and
+errors) are recorded in memory, so tests can assert that handlers are
+instrumented as expected. Requests can be dispatched using Rocket's local
+client, and each transaction is recorded once its response has been sent:
+
+```rust
+use rocket::local::blocking::Client;
+use rocket_newrelic::{NewRelic, RecordedAttribute, Transaction};
+
+#[rocket::get("/users/<id>")]
+fn get_user(transaction: &Transaction, id: i32) -> &'static str {
+    transaction.add_attribute("user_id", id);
+    "Found"
+}
+
+let (newrelic, recorder) = NewRelic::mock();
+let rocket = rocket::build()
+    .attach(newrelic)
+    .mount("/", rocket::routes![get_user]);
+let client = Client::tracked(rocket).expect("valid rocket");
+client.get("/users/42").dispatch();
+
+let transactions = recorder.transactions();
+assert_eq!(transactions.len(), 1);
+assert_eq!(transactions[0].name, "get_user");
+assert_eq!(
+    transactions[0].attribute("user_id"),
+    Some(&RecordedAttribute::Int(42))
+);
+assert_eq!(
+    transactions[0].attribute("request.route"),
+    Some(&RecordedAttribute::String("/users/<id>".to_string()))
+);
+```
+
+### Shutdown
+
+The New Relic C SDK doesn't buffer any telemetry in the app itself: each
+transaction's data is sent to the [New Relic daemon] as soon as the
+transaction ends, which the fairing does in its response callback, before the
+response is sent (or once a task spawned by the handler has finished with
+the transaction, if it's still in use). Rocket's graceful shutdown (triggered by `SIGTERM` and
+`SIGINT` by default) waits for in-flight requests to complete, so no
+additional shutdown step is needed. Harvesting data from the daemon to New
+Relic is handled by the daemon, so make sure it's given time to shut down
+cleanly too.
+
+If a request is dropped before its response is sent, e.g. because the client
+disconnected, the response callback doesn't run. The transaction is then
+left to end (without the response attributes) once the request and any
+[`OwnedTransaction`]s are dropped, as `newrelic::Transaction` ends itself
+when it's dropped. That can't be tested without a New Relic daemon, but
+mock fairings imitate it by recording transactions which are dropped
+without being finished, so tests can check what was recorded for such
+requests.
 
 [Rocket]: rocket::Rocket
 [Transaction]: crate::Transaction
 [newrelic]: https://github.com/sd2k/newrelic
 [newrelic-sys]: https://github.com/sd2k/newrelic-sys
+[tracing]: https://docs.rs/tracing
 [New Relic daemon]: https://docs.newrelic.com/docs/agents/c-sdk/get-started/introduction-c-sdk#architecture
 */
 #![deny(missing_docs)]
 use std::{
     borrow::Cow,
+    collections::hash_map::RandomState,
+    convert::TryFrom,
     env,
-    sync::{Arc, RwLock},
+    ffi::CString,
+    fmt::Display,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use rocket::{
-    fairing::{Fairing, Info, Kind},
+    data::{self, FromData},
+    fairing::{self, Fairing, Info, Kind},
+    http::{Method, Status},
     outcome::Outcome,
     request::{self, FromRequest},
-    Data, Request, Response,
+    response::{self, Responder},
+    tokio::sync::{OwnedRwLockReadGuard, RwLock, RwLockReadGuard, TryLockError},
+    Build, Data, Ignite, Request, Response, Rocket, Route, Sentinel,
 };
 
-mod error {
-    use newrelic::Error as NewRelicError;
+/// Errors returned when creating the fairing.
+pub mod error {
+    pub use newrelic::Error as NewRelicError;
     use std::{env::VarError, fmt};
 
+    /// An error creating the New Relic fairing.
     #[derive(Debug)]
     pub enum Error {
+        /// The New Relic SDK returned an error, e.g. while registering the
+        /// app with the daemon.
         NewRelicError(NewRelicError),
+        /// A required environment variable was missing or invalid.
         VarError(VarError),
     }
 
+    impl Error {
+        /// The underlying New Relic SDK error, if this error came from the SDK.
+        ///
+        /// This can be matched on to distinguish between, for example, an
+        /// invalid license key ([`NewRelicError::ConfigError`]) and a daemon
+        /// connection problem ([`NewRelicError::DaemonError`]).
+        pub fn newrelic_error(&self) -> Option<&NewRelicError> {
+            match self {
+                Self::NewRelicError(e) => Some(e),
+                Self::VarError(_) => None,
+            }
+        }
+
+        /// The underlying environment variable error, if this error came from
+        /// reading the environment.
+        pub fn var_error(&self) -> Option<&VarError> {
+            match self {
+                Self::NewRelicError(_) => None,
+                Self::VarError(e) => Some(e),
+            }
+        }
+    }
+
     impl From<NewRelicError> for Error {
         fn from(other: NewRelicError) -> Self {
             Self::NewRelicError(other)
@@ -184,14 +370,50 @@ mod error {
         }
     }
 
-    impl std::error::Error for Error {}
+    impl std::error::Error for Error {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::NewRelicError(e) => Some(e),
+                Self::VarError(e) => Some(e),
+            }
+        }
+    }
+}
+
+/// Whether the New Relic SDK has been initialized with custom config.
+static SDK_INITIALIZED: Mutex<bool> = Mutex::new(false);
+
+/// Initialize the New Relic SDK with custom config, unless it's already been
+/// initialized, since the SDK can only be initialized once.
+fn init_sdk(config: newrelic::NewRelicConfig) -> newrelic::Result<()> {
+    let mut initialized = SDK_INITIALIZED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if *initialized {
+        debug!("New Relic SDK already initialized; ignoring config");
+        return Ok(());
+    }
+    config.init()?;
+    *initialized = true;
+    Ok(())
 }
 
 #[must_use]
 /// A Rocket fairing which instruments requests using New Relic.
 ///
+/// Clones are cheap and share the same New Relic app and configuration, so
+/// the fairing can be both attached and managed, e.g.
+/// `.attach(newrelic.clone()).manage(newrelic)`.
+///
 /// See the library documentation for more details on usage.
-pub struct NewRelic(Arc<newrelic::App>);
+#[derive(Clone)]
+pub struct NewRelic {
+    app: AppHandle,
+    /// Apps used instead of `app` for requests under a mount point, as
+    /// configured by `NewRelicBuilder::app_for`.
+    mounted_apps: Vec<(String, AppHandle)>,
+    config: Arc<Config>,
+}
 
 impl NewRelic {
     /// Create a new New Relic fairing with the default New Relic SDK settings.
@@ -199,13 +421,62 @@ impl NewRelic {
     /// # Errors
     ///
     /// Will return `Err` if the license key is invalid or can't be registered
-    /// with New Relic.
+    /// with New Relic. Use [`error::Error::newrelic_error`] to find out which.
     pub fn new(app_name: &str, license_key: &str) -> Result<Self, error::Error> {
+        Self::register(app_name, license_key, Config::default())
+    }
+
+    /// Create a new New Relic fairing, retrying registration if it fails.
+    ///
+    /// Registration is attempted up to `attempts` times (at least once),
+    /// waiting `delay` between each attempt. This is useful when the daemon
+    /// may not be ready yet when the app starts, e.g. in containers.
+    ///
+    /// Note that this blocks the current thread while waiting.
+    ///
+    /// # Errors
+    ///
+    /// Will return the last `Err` if registration fails on every attempt.
+    pub fn new_with_retry(
+        app_name: &str,
+        license_key: &str,
+        attempts: u32,
+        delay: Duration,
+    ) -> Result<Self, error::Error> {
+        let attempts = attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            match Self::new(app_name, license_key) {
+                Ok(newrelic) => return Ok(newrelic),
+                Err(e) if attempt < attempts => {
+                    warn!(
+                        "New Relic registration attempt {} of {} failed, retrying in {:?}: {}",
+                        attempt, attempts, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Create a builder, allowing the instrumentation performed by the
+    /// fairing to be customised.
+    pub fn builder<'a>() -> NewRelicBuilder<'a> {
+        NewRelicBuilder::default()
+    }
+
+    fn register(app_name: &str, license_key: &str, config: Config) -> Result<Self, error::Error> {
         // Register application with New Relic
-        match newrelic::App::new(app_name, license_key) {
+        match config.build_app(app_name, license_key) {
             Ok(app) => {
                 info!("Registered with New Relic using app name {}", app_name);
-                Ok(Self(Arc::new(app)))
+                Ok(Self {
+                    app: AppHandle::Sdk(Arc::new(app)),
+                    mounted_apps: Vec::new(),
+                    config: Arc::new(config),
+                })
             }
             Err(e) => {
                 warn!("Failed to register with New Relic: {}", e);
@@ -214,10 +485,64 @@ impl NewRelic {
         }
     }
 
+    /// Create a mock New Relic fairing, which doesn't use the New Relic SDK
+    /// or daemon.
+    ///
+    /// Transactions are recorded in memory instead, and can be inspected
+    /// using the returned [`MockRecorder`] once each request has completed.
+    /// This is intended for testing that handlers instrument requests as
+    /// expected:
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, RecordedAttribute, Transaction};
+    ///
+    /// #[rocket::get("/user/me")]
+    /// fn get_me(transaction: &Transaction) -> &'static str {
+    ///     transaction.add_attribute("user_id", 123);
+    ///     "It's me!"
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/root", rocket::routes![get_me]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/root/user/me").dispatch();
+    ///
+    /// let transactions = recorder.transactions();
+    /// assert_eq!(transactions[0].name, "root/get_me");
+    /// assert!(transactions[0]
+    ///     .attributes
+    ///     .contains(&("user_id".to_string(), RecordedAttribute::Int(123))));
+    /// ```
+    ///
+    /// Use [`NewRelicBuilder::build_mock`] to combine this with other
+    /// options.
+    pub fn mock() -> (Self, MockRecorder) {
+        Self::builder().build_mock()
+    }
+
+    /// The app used to instrument requests to `path`.
+    fn app_for(&self, path: &str) -> &AppHandle {
+        self.mounted_apps
+            .iter()
+            .filter(|(base, _)| match path.strip_prefix(base.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .max_by_key(|(base, _)| base.len())
+            .map_or(&self.app, |(_, app)| app)
+    }
+
     /// Create a New Relic fairing with some custom New Relic SDK configuration.
     ///
     /// This allows settings such as the SDK log level and destination,
-    /// timeout, and daemon socket to be configured.
+    /// timeout, and daemon socket to be configured. Use
+    /// [`NewRelicBuilder::config`] to combine this with other options.
+    ///
+    /// The SDK can only be initialized once, so if this is called more than
+    /// once (e.g. in tests) the config is only used the first time.
     ///
     /// # Errors
     ///
@@ -228,8 +553,7 @@ impl NewRelic {
         license_key: &str,
         config: newrelic::NewRelicConfig,
     ) -> Result<Self, error::Error> {
-        config.init()?;
-        Self::new(app_name, license_key)
+        Self::builder().config(config).build(app_name, license_key)
     }
 
     /// Create a New Relic fairing, fetching config from the environment.
@@ -244,6 +568,11 @@ impl NewRelic {
     /// Optional
     ///
     /// - `NEW_RELIC_LOG_LEVEL` - must be able to be parsed to a `log::Level`.
+    /// - `NEW_RELIC_DAEMON_SOCKET` - the socket used to connect to the daemon.
+    /// - `NEW_RELIC_STARTUP_TIMEOUT` - the time, in milliseconds, to wait
+    ///   for a response from the daemon when initializing the SDK.
+    ///
+    /// The SDK defaults are used for any variables which aren't set.
     ///
     /// # Errors
     ///
@@ -255,245 +584,3369 @@ impl NewRelic {
         let app_name = env::var("NEW_RELIC_APP_NAME")?;
         let license_key = env::var("NEW_RELIC_LICENSE_KEY")?;
 
-        let log_level = env::var("NEW_RELIC_LOG_LEVEL");
-        if let Ok(level) = log_level {
-            let level = level.parse().unwrap_or_else(|_| {
-                warn!("Invalid value for NEW_RELIC_LOG_LEVEL; defaulting to Info");
-                log::Level::Info
+        let log_level = env::var("NEW_RELIC_LOG_LEVEL").ok();
+        let socket = env::var("NEW_RELIC_DAEMON_SOCKET").ok();
+        let timeout = env::var("NEW_RELIC_STARTUP_TIMEOUT")
+            .ok()
+            .and_then(|timeout| match timeout.parse() {
+                Ok(millis) => Some(Duration::from_millis(millis)),
+                Err(_) => {
+                    warn!("Invalid value for NEW_RELIC_STARTUP_TIMEOUT; using the default");
+                    None
+                }
             });
-            newrelic::NewRelicConfig::default()
-                .logging(level, newrelic::LogOutput::StdErr)
-                .init()?;
+        if log_level.is_some() || socket.is_some() || timeout.is_some() {
+            let mut config = newrelic::NewRelicConfig::default();
+            if let Some(level) = log_level {
+                let level = level.parse().unwrap_or_else(|_| {
+                    warn!("Invalid value for NEW_RELIC_LOG_LEVEL; defaulting to Info");
+                    log::Level::Info
+                });
+                config = config.logging(level, newrelic::LogOutput::StdErr);
+            }
+            if let Some(socket) = &socket {
+                config = config.socket(socket);
+            }
+            if let Some(timeout) = timeout {
+                config = config.timeout(timeout);
+            }
+            init_sdk(config)?;
         }
         Self::new(&app_name, &license_key)
     }
-}
 
-#[rocket::async_trait]
-impl Fairing for NewRelic {
-    fn info(&self) -> Info {
-        Info {
-            name: "New Relic instrumentation",
-            kind: Kind::Request | Kind::Response,
+    /// Reconfigure the New Relic SDK's logging, e.g. to temporarily increase
+    /// its verbosity while debugging.
+    ///
+    /// This can be called at any time, including while the app is running.
+    /// Any log file previously used by the SDK is closed. It has no effect on
+    /// mock fairings.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the log file path isn't valid Unicode, or if the
+    /// SDK fails to reconfigure logging.
+    pub fn set_log_level(
+        &self,
+        level: log::Level,
+        output: newrelic::LogOutput,
+    ) -> Result<(), error::Error> {
+        if let AppHandle::Mock(_) = self.app {
+            return Ok(());
+        }
+        let filename = match output {
+            newrelic::LogOutput::StdErr => "stderr",
+            newrelic::LogOutput::StdOut => "stdout",
+            newrelic::LogOutput::File(path) => {
+                path.to_str().ok_or(newrelic::Error::LogFileError)?
+            }
+        };
+        let filename = CString::new(filename).map_err(newrelic::Error::from)?;
+        let level = match level {
+            log::Level::Error => newrelic_sys::_newrelic_loglevel_t_NEWRELIC_LOG_ERROR,
+            log::Level::Warn => newrelic_sys::_newrelic_loglevel_t_NEWRELIC_LOG_WARNING,
+            log::Level::Info => newrelic_sys::_newrelic_loglevel_t_NEWRELIC_LOG_INFO,
+            log::Level::Debug | log::Level::Trace => {
+                newrelic_sys::_newrelic_loglevel_t_NEWRELIC_LOG_DEBUG
+            }
+        };
+        // SAFETY: `filename` is a valid NUL-terminated string which outlives
+        // the call, and the SDK doesn't keep a reference to it.
+        if unsafe { newrelic_sys::newrelic_configure_log(filename.as_ptr(), level) } {
+            info!("Reconfigured New Relic SDK logging");
+            Ok(())
+        } else {
+            warn!("Failed to reconfigure New Relic SDK logging");
+            Err(newrelic::Error::LoggingError.into())
         }
     }
+}
 
-    /// Store an atomic reference to the app in the request-local cache,
-    /// so that it can be used to create a transaction if required.
-    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
-        request.local_cache(|| AppWrapper::App(Arc::clone(&self.0)));
-    }
+/// How to instrument requests which weren't handled by any route, e.g.
+/// those answered by a 404 catcher.
+///
+/// See [`NewRelicBuilder::on_unrouted`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnroutedBehavior {
+    /// Don't record a transaction for unrouted requests.
+    Skip,
+    /// Name the transaction after the request method and the number of path
+    /// segments, e.g. `GET /*/*` for a request to `/users/123`.
+    UseUri,
+    /// Give every unrouted transaction the same name.
+    Named(String),
+}
 
-    /// End the New Relic transaction, if the request has one stored.
-    ///
-    /// Also adds an error code to the transaction if the response did
-    /// not succeed.
-    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
-        if let Transaction::Running(inner) = request.local_cache(|| Transaction::None) {
-            match inner.0.write() {
-                Ok(ref mut t) => {
-                    // Record any errors
-                    let status = response.status();
-                    if !status.class().is_success() {
-                        if let Err(msg) = t.notice_error(100, &status.to_string(), "") {
-                            warn!("Could not add error to New Relic transaction: {}", msg);
-                        }
-                    }
-                    // End the transaction explicitly here.
-                    // Otherwise it ends after the response has finished being
-                    // sent to the client, when it's dropped.
-                    t.end();
-                }
-                _ => {
-                    warn!("Could not lock mutex to end transaction");
-                }
-            };
-        }
+impl Default for UnroutedBehavior {
+    fn default() -> Self {
+        Self::Named("unknown_handler".to_string())
     }
 }
 
-/// This is used to pass the app into the request-local cache. This
-/// is needed
+/// The type of New Relic transaction used to instrument a request.
 ///
-/// We need to use an `Arc<App>` here because the request-local cache requires
-/// that any references are `'static` (see
-/// [Rocket issue #1005](https://github.com/SergioBenitez/Rocket/issues/1005)).
-/// `App` isn't Clone or Copy since it contains a raw pointer to some C memory
-/// so we reference-count instead.
-enum AppWrapper {
-    App(Arc<newrelic::App>),
-    None,
+/// See [`NewRelicBuilder::transaction_kind`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransactionKind {
+    /// A web transaction, as used by default.
+    #[default]
+    Web,
+    /// A non-web (background) transaction, as used by
+    /// [`BackgroundTransaction`].
+    NonWeb,
 }
 
-/// This has to be public since it's used inside the Transaction enum,
-/// but it serves no purpose to users (since its inner field is private).
-#[doc(hidden)]
-pub struct InnerTransaction(RwLock<newrelic::Transaction>);
+/// What to do when a [`Transaction`] method can't lock the transaction, which
+/// happens if it's used while the fairing is ending it, e.g. from a task which
+/// outlives the request.
+///
+/// The operation is skipped (or run without a segment) in every case. Once
+/// the transaction has ended, operations are always skipped with only a
+/// debug log.
+///
+/// See [`NewRelicBuilder::on_lock_failure`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockFailure {
+    /// Log a warning, as used by default.
+    #[default]
+    Warn,
+    /// Panic, e.g. to catch instrumentation used too late in tests.
+    Panic,
+    /// Only log at debug level.
+    Silent,
+}
 
-/// A New Relic transaction.
+/// The operation performed by a datastore query, used by
+/// [`Transaction::datastore_segment`] and related methods.
 ///
-/// When included in a request guard, this transaction will trace
-/// the request in New Relic. Custom attributes and segments can be
-/// added using the various methods of this enum.
+/// Strings can be used in place of an `Operation`; the common operations are
+/// recognised regardless of case, so `"SELECT"` becomes
+/// [`Operation::Select`]:
 ///
-/// Note that if an error is encountered when registering the transaction
-/// with the New Relic SDK then this could be the `Transaction::None`
-/// variant, indicating that the request is not being instrumented.
-/// In this case a warning message will be logged.
-pub enum Transaction {
-    /// A running New Relic transaction.
-    Running(InnerTransaction),
+/// ```rust
+/// # use rocket_newrelic::Transaction;
+/// use newrelic::Datastore;
+/// use rocket_newrelic::Operation;
+///
+/// # fn query(transaction: &Transaction, operation: String) {
+/// transaction.datastore_segment(Datastore::Postgres, "users", Operation::Delete, "", |_| ());
+/// transaction.datastore_segment(Datastore::Postgres, "users", "SELECT", "", |_| ());
+/// transaction.datastore_segment(Datastore::Postgres, "users", &operation, "", |_| ());
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// A `select` query.
+    Select,
+    /// An `insert` query.
+    Insert,
+    /// An `update` query.
+    Update,
+    /// A `delete` query.
+    Delete,
+    /// Any other operation, with its name.
+    Other(String),
+}
 
-    /// A dummy transaction; used if the New Relic SDK
-    /// returns an error.
-    None,
+impl Operation {
+    /// The name of the operation, as recorded in the segment.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Select => "select",
+            Self::Insert => "insert",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::Other(operation) => operation,
+        }
+    }
+
+    /// The operation of a SQL query, taken from its first keyword.
+    #[cfg(any(feature = "diesel", feature = "diesel-async"))]
+    fn from_sql(sql: &str) -> Self {
+        sql.split_whitespace()
+            .next()
+            .map_or(Self::Select, |keyword| {
+                Self::from(keyword.to_lowercase().as_str())
+            })
+    }
 }
 
-impl Transaction {
-    /// Create a new transaction for a request.
+impl From<&str> for Operation {
+    fn from(operation: &str) -> Self {
+        match operation.to_lowercase().as_str() {
+            "select" => Self::Select,
+            "insert" => Self::Insert,
+            "update" => Self::Update,
+            "delete" => Self::Delete,
+            _ => Self::Other(operation.to_string()),
+        }
+    }
+}
+
+impl From<&String> for Operation {
+    fn from(operation: &String) -> Self {
+        Self::from(operation.as_str())
+    }
+}
+
+impl From<String> for Operation {
+    fn from(operation: String) -> Self {
+        Self::from(operation.as_str())
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A builder for a [`NewRelic`] fairing.
+///
+/// Created using [`NewRelic::builder`].
+#[must_use]
+#[derive(Default)]
+pub struct NewRelicBuilder<'a> {
+    config: Config,
+    sdk_config: Option<newrelic::NewRelicConfig<'a>>,
+    mounted_apps: Vec<(String, String, String)>,
+}
+
+impl<'a> NewRelicBuilder<'a> {
+    /// Use some custom New Relic SDK configuration.
     ///
-    /// The New Relic transaction will have the URL and transaction name
-    /// attributes set.
-    fn new(app: &newrelic::App, request: &Request) -> Self {
-        // Use the route handler as the transaction name.
-        // This should always be used inside a request guard so that
-        // request.route() is not None.
-        let transaction_name: String = request.route().map_or_else(
-            || "unknown_handler".to_string(),
-            |r| {
-                format!(
-                    "{}/{}",
-                    r.uri.base().trim_start_matches('/'),
-                    r.name.as_ref().unwrap_or(&Cow::Borrowed("unknown_handler"))
-                )
-            },
-        );
+    /// This allows settings such as the SDK log level and destination,
+    /// timeout, and daemon socket to be configured. The config is
+    /// initialized when the fairing is built, unless custom config has
+    /// already been initialized by another fairing, in which case it's
+    /// ignored.
+    pub fn config(mut self, config: newrelic::NewRelicConfig<'a>) -> Self {
+        self.sdk_config = Some(config);
+        self
+    }
 
-        app.web_transaction(&transaction_name).map_or_else(
-            |e| {
-                warn!("Error beginning New Relic transaction: {}", e);
-                Self::None
-            },
-            |transaction| {
-                debug!("Began New Relic transaction");
-                if let Err(e) = transaction.add_attribute("uri", &request.uri().to_string()) {
-                    warn!("Could not add uri attribute to transaction: {}", e);
-                };
-                Self::Running(InnerTransaction(RwLock::new(transaction)))
-            },
-        )
+    /// Add the host, port (or socket path or ID) and database name of the
+    /// datastore instance to every datastore segment.
+    ///
+    /// These are used by `Transaction::datastore_segment`, its async
+    /// variant, and the Diesel helpers. To use different details for a
+    /// particular query, build its parameters and pass them to
+    /// `Transaction::datastore_segment_with`.
+    pub fn datastore_defaults(
+        mut self,
+        host: &str,
+        port_path_or_id: &str,
+        database_name: &str,
+    ) -> Self {
+        self.config.datastore_defaults = Some(DatastoreDefaults {
+            host: host.to_string(),
+            port_path_or_id: port_path_or_id.to_string(),
+            database_name: database_name.to_string(),
+        });
+        self
     }
 
-    /// Add an attribute to the transaction.
-    pub fn add_attribute<'a, T>(&self, key: &str, attribute: T)
-    where
-        T: Into<newrelic::Attribute<'a>>,
-    {
-        if let Self::Running(inner) = self {
-            match inner.0.read() {
-                Ok(t) => {
-                    match t.add_attribute(key, attribute) {
-                        Ok(_) => debug!("Successfully added attribute"),
-                        Err(e) => debug!("Could not add attribute to transaction: {}", e),
-                    };
-                }
-                Err(e) => {
-                    warn!("Error locking transaction RwLock: {}", e);
-                }
-            };
-        }
+    /// Exclude requests to routes mounted at `path` from instrumentation.
+    ///
+    /// The pattern is matched against the full mounted path of the route,
+    /// e.g. `/health`. A trailing `*` matches any route whose path begins
+    /// with the rest of the pattern, so `/internal/*` would ignore all routes
+    /// mounted under `/internal/`.
+    pub fn ignore_path(mut self, path: &str) -> Self {
+        self.config.ignored_paths.push(path.to_string());
+        self
     }
 
-    /// Execute the function in a named custom segment.
+    /// Use `kind` of transaction for requests instrumented using the
+    /// [`Transaction`] request guard (or `instrument_all`).
     ///
-    /// `func` should be a function taking a `newrelic::Segment`. This allows
-    /// nested segments to be created using methods of the passed segment.
+    /// This is [`TransactionKind::Web`] by default. The
+    /// [`BackgroundTransaction`] request guard always begins a non-web
+    /// transaction.
+    pub fn transaction_kind(mut self, kind: TransactionKind) -> Self {
+        self.config.transaction_kind = kind;
+        self
+    }
+
+    /// Use `kind` of transaction for requests to `path`, overriding
+    /// [`NewRelicBuilder::transaction_kind`], e.g. to record long-lived
+    /// event streams as non-web transactions:
     ///
-    /// If the current transaction could not be registered, this just calls the
-    /// given function outside of a segment.
-    pub fn custom_segment<F, V>(&self, name: &str, category: &str, func: F) -> V
-    where
-        F: FnOnce(newrelic::Segment) -> V,
-    {
-        match self {
-            Transaction::Running(inner) => match inner.0.read() {
-                Ok(t) => t.custom_segment(name, category, func),
-                Err(e) => {
-                    warn!("Error locking transaction RwLock: {}", e);
-                    func(newrelic::Segment::default())
-                }
-            },
-            Transaction::None => func(newrelic::Segment::default()),
-        }
+    /// ```rust
+    /// # use rocket_newrelic::{NewRelic, TransactionKind};
+    /// let builder = NewRelic::builder().path_transaction_kind("/events/*", TransactionKind::NonWeb);
+    /// ```
+    ///
+    /// Patterns are as for [`NewRelicBuilder::ignore_path`], except they're
+    /// matched against the request path, since the transaction may begin
+    /// before the request is routed. The first matching pattern is used.
+    pub fn path_transaction_kind(mut self, path: &str, kind: TransactionKind) -> Self {
+        self.config.path_kinds.push((path.to_string(), kind));
+        self
     }
 
-    /// Execute the function in a datastore segment.
+    /// Add an attribute with a fixed value to every transaction, e.g. the
+    /// deployment version or environment.
     ///
-    /// `func` should be a function taking a `newrelic::Segment`. This allows
-    /// nested segments to be created using methods of the passed segment.
+    /// These are added before any per-request attributes.
+    pub fn global_attribute(mut self, key: &str, value: &str) -> Self {
+        self.config
+            .global_attributes
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Record the value of the named request header as an attribute of every
+    /// transaction, under the key `request.header.<name>`.
     ///
-    /// The `table` argument should not contain any slash characters.
+    /// Requests which don't include the header are skipped.
+    pub fn capture_header(mut self, name: &str) -> Self {
+        self.config.captured_headers.push(name.to_string());
+        self
+    }
+
+    /// Record the value of the named response header as an attribute of every
+    /// transaction, under the key `response.header.<name>`, e.g. to group
+    /// transactions by an `X-Cache` header.
     ///
-    /// If the current transaction could not be registered, this just calls the
-    /// given function with a `newrelic::Segment::None`.
+    /// Responses which don't include the header are skipped.
+    pub fn capture_response_header(mut self, name: &str) -> Self {
+        self.config.captured_response_headers.push(name.to_string());
+        self
+    }
+
+    /// Record the value of the named query string parameter as an attribute
+    /// of every transaction, under the key `request.query.<name>`.
     ///
-    /// See `newrelic::DatastoreParamsBuilder` and
-    /// `newrelic::Transaction::datastore_segment` for more details.
+    /// Requests which don't include the parameter are skipped.
+    pub fn capture_query_param(mut self, name: &str) -> Self {
+        self.config.captured_query_params.push(name.to_string());
+        self
+    }
+
+    /// Record the client's IP address as an attribute of every transaction,
+    /// under the key `request.client_ip`.
     ///
-    /// SQL Obfuscation
-    /// ---------------
+    /// This uses `Request::client_ip`, so respects Rocket's `ip_header`
+    /// setting (`X-Real-IP` by default); requests where the address isn't
+    /// known are skipped. To also record the `X-Forwarded-For` chain, use
+    /// `capture_header("X-Forwarded-For")`.
     ///
-    /// The supplied SQL string will go through the New Relic SDK's
-    /// basic literal replacement obfuscator that strips the SQL
-    /// string literals (values between single or double quotes) and
-    /// numeric sequences, replacing them with the ? character.
-    /// For example:
+    /// This is disabled by default.
+    pub fn capture_client_ip(mut self, enabled: bool) -> Self {
+        self.config.capture_client_ip = enabled;
+        self
+    }
+
+    /// Record the value of the named cookie as an attribute of every
+    /// transaction, under the key `request.cookie.<name>`.
     ///
-    /// This SQL:
-    ///      SELECT * FROM table WHERE ssn=‘000-00-0000’
+    /// Only cookies named using this method are recorded, so cookies with
+    /// sensitive values such as session tokens can be left out. Requests
+    /// which don't include the cookie are skipped.
+    pub fn capture_cookie(mut self, name: &str) -> Self {
+        self.config.captured_cookies.push(name.to_string());
+        self
+    }
+
+    /// Record the values of the dynamic path segments of the route handling
+    /// each request as attributes, under the keys `request.param.<name>`.
     ///
-    /// obfuscates to:
-    ///      SELECT * FROM table WHERE ssn=?
+    /// The transaction keeps the route's name, so values such as IDs can be
+    /// inspected without each one producing a separate transaction name. A
+    /// trailing `<name..>` segment is recorded as the rest of the path.
     ///
-    /// Because the default obfuscator just replaces literals, there
-    /// could be cases that it does not handle well. For instance, it
-    /// will not strip out comments from your SQL string, it will not
-    /// handle certain database-specific language features, and it
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, RecordedAttribute, Transaction};
+    ///
+    /// #[rocket::get("/user/<id>")]
+    /// fn get_user(id: u32, _transaction: &Transaction) -> String {
+    ///     id.to_string()
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder().capture_path_params(true).build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![get_user]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/user/42").dispatch();
+    ///
+    /// assert_eq!(
+    ///     recorder.transactions()[0].attribute("request.param.id"),
+    ///     Some(&RecordedAttribute::String("42".to_string())),
+    /// );
+    /// ```
+    ///
+    /// This is disabled by default.
+    pub fn capture_path_params(mut self, enabled: bool) -> Self {
+        self.config.capture_path_params = enabled;
+        self
+    }
+
+    /// Only record detailed attributes for transactions which take at least
+    /// `threshold_ms` milliseconds, to reduce the volume of data sent for
+    /// fast requests.
+    ///
+    /// Detailed attributes are the captured headers, query parameters,
+    /// cookies and client IP, and any added using
+    /// `Transaction::add_attribute`. They're held back until the response
+    /// has been sent, then added if the transaction was slow enough. The
+    /// URL, method, route and response attributes are always recorded.
+    pub fn detail_threshold_ms(mut self, threshold_ms: u64) -> Self {
+        self.config.detail_threshold = Some(Duration::from_millis(threshold_ms));
+        self
+    }
+
+    /// Add the rank and format of the route handling each request to its
+    /// transaction, as the `request.route.rank` and `request.route.format`
+    /// attributes.
+    ///
+    /// This can help to diagnose which of several overlapping routes handled
+    /// a request. The format is only added for routes which specify one.
+    ///
+    /// This is disabled by default.
+    pub fn debug_attributes(mut self, enabled: bool) -> Self {
+        self.config.debug_attributes = enabled;
+        self
+    }
+
+    /// Choose what happens when a [`Transaction`] method can't lock the
+    /// transaction (see [`LockFailure`]).
+    ///
+    /// This is [`LockFailure::Warn`] by default.
+    pub fn on_lock_failure(mut self, behavior: LockFailure) -> Self {
+        self.config.lock_failure = behavior;
+        self
+    }
+
+    /// Begin a web transaction for every request as it arrives, rather than
+    /// only for requests handled by routes using a [`Transaction`] guard.
+    ///
+    /// Handlers can still use the guard to access the transaction, which
+    /// will return the transaction begun for the request. Requests to
+    /// ignored paths are still ignored.
+    ///
+    /// This is disabled by default.
+    pub fn instrument_all(mut self, enabled: bool) -> Self {
+        self.config.instrument_all = enabled;
+        self
+    }
+
+    /// Only instrument requests using one of the given methods, e.g. to
+    /// focus on requests which change state.
+    ///
+    /// Requests using any other method aren't instrumented. By default
+    /// requests using any method are instrumented.
+    pub fn only_methods(mut self, methods: &[Method]) -> Self {
+        self.config.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Choose how to instrument requests which aren't handled by any route.
+    ///
+    /// By default these are all named `unknown_handler`. This only affects
+    /// requests which are instrumented despite not being routed, such as
+    /// those begun by [`NewRelicBuilder::instrument_all`].
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, UnroutedBehavior};
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder()
+    ///     .instrument_all(true)
+    ///     .on_unrouted(UnroutedBehavior::UseUri)
+    ///     .build_mock();
+    /// let client = Client::tracked(rocket::build().attach(newrelic)).unwrap();
+    /// client.get("/users/123").dispatch();
+    ///
+    /// assert_eq!(recorder.transactions()[0].name, "GET /*/*");
+    /// ```
+    pub fn on_unrouted(mut self, behavior: UnroutedBehavior) -> Self {
+        self.config.unrouted = behavior;
+        self
+    }
+
+    /// Enable distributed tracing for the New Relic app.
+    ///
+    /// This is disabled by default.
+    ///
+    /// *Note*: the underlying `newrelic` crate doesn't yet expose the SDK's
+    /// API for accepting inbound distributed trace payloads, so incoming
+    /// `traceparent`/`tracestate` or `newrelic` headers aren't linked to the
+    /// transaction yet.
+    ///
+    /// *Note*: requires the `distributed_tracing` feature.
+    #[cfg(feature = "distributed_tracing")]
+    pub fn distributed_tracing(mut self, enabled: bool) -> Self {
+        self.config.distributed_tracing = enabled;
+        self
+    }
+
+    /// Only record an error in the transaction for responses where
+    /// `predicate` returns `true` for the response status.
+    ///
+    /// By default an error is recorded for any status which isn't a success
+    /// (2xx), including redirects and client errors. To only record server
+    /// errors, for example:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::NewRelic;
+    /// use rocket::http::{Status, StatusClass};
+    ///
+    /// let builder = NewRelic::builder()
+    ///     .error_when(|status: Status| status.class() == StatusClass::ServerError);
+    /// ```
+    pub fn error_when(mut self, predicate: fn(Status) -> bool) -> Self {
+        self.config.error_when = predicate;
+        self
+    }
+
+    /// Choose the class of the error recorded for a response status using
+    /// `class`, where returning `None` means no error is recorded.
+    ///
+    /// This replaces [`NewRelicBuilder::error_when`], allowing different
+    /// statuses to be grouped into different error classes rather than all
+    /// being reported as `HttpError`:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::NewRelic;
+    /// use rocket::http::Status;
+    ///
+    /// let builder = NewRelic::builder().error_class(|status: Status| match status.code {
+    ///     429 => Some("RateLimited".to_string()),
+    ///     503 => Some("Unavailable".to_string()),
+    ///     500..=599 => Some("HttpError".to_string()),
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn error_class(mut self, class: fn(Status) -> Option<String>) -> Self {
+        self.config.error_class = Some(class);
+        self
+    }
+
+    /// Compute the message of the error recorded for unsuccessful responses
+    /// (see [`NewRelicBuilder::error_when`] and
+    /// [`NewRelicBuilder::error_class`]) using `message`.
+    ///
+    /// By default the message is the response status, e.g. `500 Internal
+    /// Server Error`. To include the request method and path:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::NewRelic;
+    /// use rocket::{Request, Response};
+    ///
+    /// let builder = NewRelic::builder().error_message(|request: &Request, response: &Response| {
+    ///     format!("{} on {} {}", response.status(), request.method(), request.uri().path())
+    /// });
+    /// ```
+    pub fn error_message(mut self, message: fn(&Request<'_>, &Response<'_>) -> String) -> Self {
+        self.config.error_message = message;
+        self
+    }
+
+    /// Strip slashes from datastore tables and operations, and external
+    /// procedures and libraries, before creating segments.
+    ///
+    /// The New Relic SDK refuses to record segments whose parameters contain
+    /// slashes. By default such parameters are passed through unchanged, with
+    /// a warning logged; enabling this removes the slashes so the segment is
+    /// recorded instead.
+    pub fn sanitize_segment_names(mut self, enabled: bool) -> Self {
+        self.config.sanitize_segment_names = enabled;
+        self
+    }
+
+    /// Generate transaction names from the route handling the request using
+    /// `format`.
+    ///
+    /// By default the route's base (without the leading slash) and handler
+    /// name are joined with a `/`, e.g. `root/get_me`. To use just the
+    /// handler name, for example:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::NewRelic;
+    /// use rocket::Route;
+    ///
+    /// let builder = NewRelic::builder().name_format(|route: &Route| {
+    ///     route.name.as_deref().unwrap_or("unknown_handler").to_string()
+    /// });
+    /// ```
+    pub fn name_format(mut self, format: fn(&Route) -> String) -> Self {
+        self.config.name_format = Some(format);
+        self
+    }
+
+    /// Add the extension of the request's format to the names of transactions
+    /// for routes, e.g. `user/get_me.json` or `user/get_me.html`, so that
+    /// each representation of a content-negotiated route can be told apart.
+    ///
+    /// The format is the `Accept` header for requests without bodies, and
+    /// the `Content-Type` for those with bodies, as for `Request::format`.
+    /// Nothing is added if the request has no format, or it doesn't have a
+    /// well-known extension (including `*/*`).
+    ///
+    /// ```rust
+    /// use rocket::{http::Accept, local::blocking::Client};
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// #[rocket::get("/me")]
+    /// fn get_me(_transaction: &Transaction) {}
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder().include_format_in_name(true).build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/user", rocket::routes![get_me]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/user/me").header(Accept::JSON).dispatch();
+    /// client.get("/user/me").dispatch();
+    ///
+    /// let transactions = recorder.transactions();
+    /// assert_eq!(transactions[0].name, "user/get_me.json");
+    /// assert_eq!(transactions[1].name, "user/get_me");
+    /// ```
+    pub fn include_format_in_name(mut self, enabled: bool) -> Self {
+        self.config.include_format_in_name = enabled;
+        self
+    }
+
+    /// Remove `prefix` from the start of route bases when generating the
+    /// default transaction names.
+    ///
+    /// For example, with `strip_base_prefix("/api/v1")` a handler named
+    /// `get_user` mounted at `/api/v1/users` is named `users/get_user`
+    /// rather than `api/v1/users/get_user`. Bases which don't start with the
+    /// prefix are left unchanged.
+    ///
+    /// This has no effect if a custom [`NewRelicBuilder::name_format`] is
+    /// used.
+    pub fn strip_base_prefix(mut self, prefix: &str) -> Self {
+        self.config.base_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Prefix each transaction's name with `prefix`, e.g. to distinguish the
+    /// transactions of several services reporting to the same New Relic app.
+    ///
+    /// With `name_prefix("auth-service")` a handler named `login` mounted at
+    /// `/user` is named `auth-service/user/login`. This applies to custom
+    /// [`NewRelicBuilder::name_format`] names too, but not to names set
+    /// explicitly using `Transaction::set_name`.
+    pub fn name_prefix(mut self, prefix: &str) -> Self {
+        self.config.name_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Name transactions for the route named `route` (usually its handler's
+    /// name) `name`, rather than generating a name from its path.
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// #[rocket::get("/me")]
+    /// fn get_me(_transaction: &Transaction) {}
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder()
+    ///     .rename("get_me", "User Profile")
+    ///     .build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/user", rocket::routes![get_me]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/user/me").dispatch();
+    ///
+    /// assert_eq!(recorder.transactions()[0].name, "User Profile");
+    /// ```
+    ///
+    /// This takes precedence over [`NewRelicBuilder::name_format`], but the
+    /// [`NewRelicBuilder::name_prefix`] is still added.
+    pub fn rename(mut self, route: &str, name: &str) -> Self {
+        self.config
+            .renamed_routes
+            .push((route.to_string(), name.to_string()));
+        self
+    }
+
+    /// Limit the number of segments each transaction can have open at once,
+    /// e.g. to protect the daemon from runaway recursion.
+    ///
+    /// Once `count` segments are open, the segment methods of [`Transaction`]
+    /// just run the given function without creating a segment. Every open
+    /// segment counts towards the limit, whether it's nested in another or
+    /// not, so sibling segments open at the same time (e.g. async segments
+    /// awaited concurrently) count as much as nested ones. Segments created
+    /// using the methods of `newrelic::Segment` aren't counted.
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// fn walk(transaction: &Transaction, depth: u32) {
+    ///     if depth > 0 {
+    ///         transaction.custom_segment("walk", "tree", |_| walk(transaction, depth - 1));
+    ///     }
+    /// }
+    ///
+    /// #[rocket::get("/tree")]
+    /// fn tree(transaction: &Transaction) {
+    ///     walk(transaction, 10);
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder().max_open_segments(3).build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![tree]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/tree").dispatch();
+    ///
+    /// assert_eq!(recorder.transactions()[0].segments.len(), 3);
+    /// ```
+    pub fn max_open_segments(mut self, count: usize) -> Self {
+        self.config.max_open_segments = Some(count);
+        self
+    }
+
+    /// Truncate string attribute values longer than `length` bytes,
+    /// replacing the end of the value with `...`.
+    ///
+    /// New Relic drops attributes whose values are too long (255 bytes by
+    /// default), so this keeps long values such as URIs visible. This applies
+    /// to the attributes added automatically as well as those added using
+    /// [`Transaction::add_attribute`]. Non-string attributes are unaffected.
+    ///
+    /// Values are never longer than `length` once truncated, so if it's less
+    /// than 3 they're cut short without the `...`.
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, RecordedAttribute, Transaction};
+    ///
+    /// #[rocket::get("/")]
+    /// fn index(transaction: &Transaction) -> &'static str {
+    ///     transaction.add_attribute("letters", "abcdefgh");
+    ///     "Hello"
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder().max_attribute_length(6).build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![index]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.get("/").dispatch();
+    ///
+    /// assert_eq!(
+    ///     recorder.transactions()[0].attribute("letters"),
+    ///     Some(&RecordedAttribute::String("abc...".to_string())),
+    /// );
+    /// ```
+    pub fn max_attribute_length(mut self, length: usize) -> Self {
+        self.config.max_attribute_length = Some(length);
+        self
+    }
+
+    /// Replace the value of any attribute added with the given key by
+    /// `"[REDACTED]"`, to avoid accidentally sending sensitive data such
+    /// as passwords to New Relic.
+    ///
+    /// This applies to the attributes added automatically, such as captured
+    /// headers, as well as those added using [`Transaction::add_attribute`]:
+    ///
+    /// ```rust
+    /// use rocket::{http::Header, local::blocking::Client};
+    /// use rocket_newrelic::{NewRelic, RecordedAttribute, Transaction};
+    ///
+    /// #[rocket::get("/account")]
+    /// fn account(transaction: &Transaction) -> &'static str {
+    ///     transaction.add_attribute("password", "hunter2");
+    ///     "Account"
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::builder()
+    ///     .capture_header("Authorization")
+    ///     .redact_attribute("request.header.Authorization")
+    ///     .redact_attribute("password")
+    ///     .build_mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![account]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client
+    ///     .get("/account")
+    ///     .header(Header::new("Authorization", "secret"))
+    ///     .dispatch();
+    ///
+    /// let transaction = &recorder.transactions()[0];
+    /// let redacted = RecordedAttribute::String("[REDACTED]".to_string());
+    /// assert_eq!(transaction.attribute("request.header.Authorization"), Some(&redacted));
+    /// assert_eq!(transaction.attribute("password"), Some(&redacted));
+    /// ```
+    pub fn redact_attribute(mut self, key: &str) -> Self {
+        self.config.redacted_attributes.push(key.to_string());
+        self
+    }
+
+    /// Report requests to routes mounted at `base` to a separate New Relic
+    /// app, rather than the app the fairing is built with.
+    ///
+    /// This can be used to segregate telemetry, e.g. per tenant:
+    ///
+    /// ```rust,no_run
+    /// # use rocket_newrelic::NewRelic;
+    /// let newrelic = NewRelic::builder()
+    ///     .app_for("/tenant_a", "My App (Tenant A)", "TENANT_A_LICENSE_KEY")
+    ///     .app_for("/tenant_b", "My App (Tenant B)", "TENANT_B_LICENSE_KEY")
+    ///     .build("My App", "MY_LICENSE_KEY")
+    ///     .expect("Could not register with New Relic");
+    /// ```
+    ///
+    /// Requests are matched against `base` by path segment, so `/tenant_a`
+    /// matches `/tenant_a/users` but not `/tenant_ab`. If several bases
+    /// match, the longest is used. All apps share the other options of the
+    /// builder.
+    pub fn app_for(mut self, base: &str, app_name: &str, license_key: &str) -> Self {
+        self.mounted_apps.push((
+            base.trim_end_matches('/').to_string(),
+            app_name.to_string(),
+            license_key.to_string(),
+        ));
+        self
+    }
+
+    /// Only instrument the given proportion of requests, between `0.0`
+    /// (none) and `1.0` (all), to reduce the overhead and volume of data
+    /// for very busy apps.
+    ///
+    /// Each request is sampled at random when its transaction would be
+    /// begun. Requests which aren't sampled aren't instrumented, so the
+    /// [`Transaction`] guard returns `Transaction::None` for them. For
+    /// example, to instrument around one in ten requests:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::NewRelic;
+    /// let builder = NewRelic::builder().sample_rate(0.1);
+    /// ```
+    ///
+    /// All requests are instrumented by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` isn't between `0.0` and `1.0`.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "sample rate must be between 0.0 and 1.0, got {}",
+            rate
+        );
+        self.config.sample_rate = rate;
+        self
+    }
+
+    /// Create the New Relic fairing with the configured options.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the license keys are invalid or can't be
+    /// registered with New Relic, or if any custom SDK config cannot be
+    /// initialized.
+    pub fn build(self, app_name: &str, license_key: &str) -> Result<NewRelic, error::Error> {
+        if let Some(sdk_config) = self.sdk_config {
+            init_sdk(sdk_config)?;
+        }
+        let mut newrelic = NewRelic::register(app_name, license_key, self.config)?;
+        for (base, app_name, license_key) in self.mounted_apps {
+            match newrelic.config.build_app(&app_name, &license_key) {
+                Ok(app) => {
+                    info!(
+                        "Registered with New Relic using app name {} for {}",
+                        app_name, base
+                    );
+                    newrelic
+                        .mounted_apps
+                        .push((base, AppHandle::Sdk(Arc::new(app))));
+                }
+                Err(e) => {
+                    warn!("Failed to register with New Relic: {}", e);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(newrelic)
+    }
+
+    /// Create a mock New Relic fairing with the configured options, which
+    /// records transactions in memory rather than using the New Relic SDK.
+    ///
+    /// Any custom SDK config is ignored, and transactions for requests under
+    /// the mount points given to [`NewRelicBuilder::app_for`] are recorded
+    /// by the same recorder. See [`NewRelic::mock`] for more
+    /// details.
+    pub fn build_mock(self) -> (NewRelic, MockRecorder) {
+        let recorder = MockRecorder::default();
+        let newrelic = NewRelic {
+            app: AppHandle::Mock(recorder.clone()),
+            mounted_apps: Vec::new(),
+            config: Arc::new(self.config),
+        };
+        (newrelic, recorder)
+    }
+}
+
+/// The value used in place of redacted attributes.
+const REDACTED: &str = "[REDACTED]";
+
+/// The datastore instance details added to every datastore segment, as
+/// configured by `NewRelicBuilder::datastore_defaults`.
+struct DatastoreDefaults {
+    host: String,
+    port_path_or_id: String,
+    database_name: String,
+}
+
+/// Options controlling how requests are instrumented.
+struct Config {
+    ignored_paths: Vec<String>,
+    transaction_kind: TransactionKind,
+    path_kinds: Vec<(String, TransactionKind)>,
+    global_attributes: Vec<(String, String)>,
+    captured_headers: Vec<String>,
+    captured_response_headers: Vec<String>,
+    captured_query_params: Vec<String>,
+    captured_cookies: Vec<String>,
+    capture_client_ip: bool,
+    capture_path_params: bool,
+    lock_failure: LockFailure,
+    datastore_defaults: Option<DatastoreDefaults>,
+    detail_threshold: Option<Duration>,
+    renamed_routes: Vec<(String, String)>,
+    include_format_in_name: bool,
+    redacted_attributes: Vec<String>,
+    error_when: fn(Status) -> bool,
+    error_class: Option<fn(Status) -> Option<String>>,
+    error_message: fn(&Request<'_>, &Response<'_>) -> String,
+    name_format: Option<fn(&Route) -> String>,
+    base_prefix: Option<String>,
+    name_prefix: Option<String>,
+    instrument_all: bool,
+    sample_rate: f64,
+    methods: Option<Vec<Method>>,
+    unrouted: UnroutedBehavior,
+    sanitize_segment_names: bool,
+    debug_attributes: bool,
+    max_attribute_length: Option<usize>,
+    max_open_segments: Option<usize>,
+    #[cfg(feature = "distributed_tracing")]
+    distributed_tracing: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ignored_paths: Vec::new(),
+            transaction_kind: TransactionKind::default(),
+            path_kinds: Vec::new(),
+            global_attributes: Vec::new(),
+            captured_headers: Vec::new(),
+            captured_response_headers: Vec::new(),
+            captured_query_params: Vec::new(),
+            captured_cookies: Vec::new(),
+            capture_client_ip: false,
+            capture_path_params: false,
+            lock_failure: LockFailure::default(),
+            datastore_defaults: None,
+            detail_threshold: None,
+            renamed_routes: Vec::new(),
+            include_format_in_name: false,
+            redacted_attributes: Vec::new(),
+            error_when: |status| !status.class().is_success(),
+            error_class: None,
+            error_message: |_, response| response.status().to_string(),
+            name_format: None,
+            base_prefix: None,
+            name_prefix: None,
+            instrument_all: false,
+            sample_rate: 1.0,
+            methods: None,
+            unrouted: UnroutedBehavior::default(),
+            sanitize_segment_names: false,
+            debug_attributes: false,
+            max_attribute_length: None,
+            max_open_segments: None,
+            #[cfg(feature = "distributed_tracing")]
+            distributed_tracing: false,
+        }
+    }
+}
+
+impl Config {
+    /// Create the New Relic app, applying any app-level settings.
+    fn build_app(&self, app_name: &str, license_key: &str) -> newrelic::Result<newrelic::App> {
+        #[allow(unused_mut)]
+        let mut builder = newrelic::AppBuilder::new(app_name, license_key)?;
+        #[cfg(feature = "distributed_tracing")]
+        builder.distributed_tracing(self.distributed_tracing);
+        builder.build()
+    }
+
+    /// Truncate a string attribute value to the maximum length, if one has
+    /// been set.
+    fn truncate<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        const ELLIPSIS: &str = "...";
+        match self.max_attribute_length {
+            Some(max) if value.len() > max => {
+                // Limits too short for the ellipsis just cut the value short.
+                let ellipsis = if max < ELLIPSIS.len() { "" } else { ELLIPSIS };
+                let mut end = max - ellipsis.len();
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Cow::Owned(format!("{}{}", &value[..end], ellipsis))
+            }
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// The truncated value of a string attribute, if it needs truncating.
+    fn truncate_attribute(&self, attribute: &newrelic::Attribute) -> Option<String> {
+        let value = match attribute {
+            newrelic::Attribute::String(s) => s,
+            newrelic::Attribute::OwnedString(s) => s.as_str(),
+            _ => return None,
+        };
+        match self.truncate(value) {
+            Cow::Owned(truncated) => Some(truncated),
+            Cow::Borrowed(_) => None,
+        }
+    }
+
+    /// Whether values of the attribute should be redacted.
+    fn is_redacted(&self, key: &str) -> bool {
+        self.redacted_attributes.iter().any(|k| k == key)
+    }
+
+    /// The value to record for an attribute, replacing it if the key has
+    /// been redacted.
+    fn redact<'a>(&self, key: &str, attribute: newrelic::Attribute<'a>) -> newrelic::Attribute<'a> {
+        if self.is_redacted(key) {
+            newrelic::Attribute::String(REDACTED)
+        } else {
+            attribute
+        }
+    }
+
+    /// Redact or truncate the values of string attributes added by the
+    /// fairing, as is done for those added using `Transaction::add_attribute`.
+    fn prepare_attributes(&self, attributes: &mut [(String, String)]) {
+        for (key, value) in attributes {
+            if self.is_redacted(key) {
+                *value = REDACTED.to_string();
+            } else if let Cow::Owned(truncated) = self.truncate(value) {
+                *value = truncated;
+            }
+        }
+    }
+
+    /// Prepare a segment parameter which mustn't contain slashes, such as a
+    /// datastore table or external procedure.
+    ///
+    /// The New Relic SDK silently drops segments with a slash in these
+    /// parameters, so they're either stripped or a warning is logged.
+    fn segment_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if !name.contains('/') {
+            Cow::Borrowed(name)
+        } else if self.sanitize_segment_names {
+            Cow::Owned(name.replace('/', ""))
+        } else {
+            warn!(
+                "Segment parameter '{}' contains a slash so the segment won't be recorded; \
+                 consider enabling sanitize_segment_names",
+                name
+            );
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// Build the parameters of a datastore segment, including the
+    /// datastore defaults, if configured.
+    fn datastore_params(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: &str,
+        sql: &str,
+    ) -> newrelic::Result<newrelic::DatastoreParams> {
+        let table = self.segment_name(table);
+        let operation = self.segment_name(operation);
+        let mut params = newrelic::DatastoreParamsBuilder::new(datastore)
+            .collection(&table)
+            .operation(&operation)
+            .query(sql);
+        if let Some(defaults) = &self.datastore_defaults {
+            params = params
+                .host(&defaults.host)
+                .port_path_or_id(&defaults.port_path_or_id)
+                .database_name(&defaults.database_name);
+        }
+        params.build()
+    }
+
+    /// The name of the transaction for a request handled by `route`.
+    fn transaction_name(&self, route: &Route, request: &Request<'_>) -> String {
+        let renamed = route.name.as_ref().and_then(|route_name| {
+            self.renamed_routes
+                .iter()
+                .find(|(r, _)| r == route_name)
+                .map(|(_, name)| name.clone())
+        });
+        let name = if let Some(name) = renamed {
+            name
+        } else if let Some(format) = self.name_format {
+            format(route)
+        } else {
+            let base = route.uri.base();
+            let base = self
+                .base_prefix
+                .as_deref()
+                .and_then(|prefix| base.strip_prefix(prefix))
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(base);
+            route_transaction_name(base, route)
+        };
+        let name = self.prefixed(name);
+        let extension = request.format().and_then(|format| format.extension());
+        match extension {
+            Some(extension) if self.include_format_in_name => {
+                format!("{}.{}", name, extension.as_str().to_lowercase())
+            }
+            _ => name,
+        }
+    }
+
+    /// Add the configured name prefix, if any, to a transaction name.
+    fn prefixed(&self, name: String) -> String {
+        match &self.name_prefix {
+            Some(prefix) => format!("{}/{}", prefix, name.trim_start_matches('/')),
+            None => name,
+        }
+    }
+
+    /// The class of the error to record for a response status, if any.
+    fn error_class(&self, status: Status) -> Option<String> {
+        match self.error_class {
+            Some(class) => class(status),
+            None if (self.error_when)(status) => Some("HttpError".to_string()),
+            None => None,
+        }
+    }
+
+    /// The attributes describing the route handling a request.
+    fn route_attributes(&self, route: &Route, request: &Request<'_>) -> Vec<(String, String)> {
+        let mut attributes = vec![("request.route".to_string(), route.uri.path().to_string())];
+        if self.debug_attributes {
+            attributes.push(("request.route.rank".to_string(), route.rank.to_string()));
+            if let Some(format) = &route.format {
+                attributes.push(("request.route.format".to_string(), format.to_string()));
+            }
+        }
+        if self.capture_path_params {
+            attributes.extend(path_params(route, request));
+        }
+        attributes
+    }
+
+    /// The name of a transaction for a request which no route handled, or
+    /// `None` if it shouldn't be instrumented.
+    fn unrouted_name(&self, request: &Request<'_>) -> Option<String> {
+        match &self.unrouted {
+            UnroutedBehavior::Skip => None,
+            UnroutedBehavior::UseUri => {
+                let segments = request.uri().path().segments().len();
+                Some(format!(
+                    "{} /{}",
+                    request.method(),
+                    vec!["*"; segments].join("/")
+                ))
+            }
+            UnroutedBehavior::Named(name) => Some(name.clone()),
+        }
+        .map(|name| self.prefixed(name))
+    }
+
+    /// Whether to instrument a request, choosing at random according to the
+    /// sample rate.
+    fn is_sampled(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        // Each `RandomState` is seeded differently, so hashing nothing with
+        // one gives a cheap random number without needing an RNG crate.
+        let random = RandomState::new().build_hasher().finish();
+        let sampled = (random as f64 / u64::MAX as f64) < self.sample_rate;
+        debug!(
+            "Request {} sampled at rate {}",
+            if sampled { "was" } else { "wasn't" },
+            self.sample_rate
+        );
+        sampled
+    }
+
+    /// Whether the route has been excluded from instrumentation.
+    fn is_ignored(&self, route: &Route) -> bool {
+        let path = route.uri.path();
+        self.ignored_paths
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+
+    /// The kind of transaction to begin for a request using the
+    /// `Transaction` request guard.
+    fn transaction_kind(&self, request: &Request<'_>) -> TransactionKind {
+        let path = request.uri().path();
+        self.path_kinds
+            .iter()
+            .find(|(pattern, _)| path_matches(pattern, path.as_str()))
+            .map_or(self.transaction_kind, |(_, kind)| *kind)
+    }
+}
+
+/// The values of the route's dynamic path segments in the request, as
+/// `request.param.<name>` attributes.
+fn path_params(route: &Route, request: &Request<'_>) -> Vec<(String, String)> {
+    let mut segments = request.uri().path().segments();
+    let mut params = Vec::new();
+    for pattern in route.uri.path().split('/').filter(|s| !s.is_empty()) {
+        let name = match pattern.strip_prefix('<').and_then(|p| p.strip_suffix('>')) {
+            Some(name) => name,
+            None => {
+                segments.next();
+                continue;
+            }
+        };
+        match name.strip_suffix("..") {
+            Some(name) => {
+                let rest: Vec<_> = segments.by_ref().collect();
+                params.push((format!("request.param.{}", name), rest.join("/")));
+                break;
+            }
+            None => {
+                if let Some(value) = segments.next() {
+                    params.push((format!("request.param.{}", name), value.to_string()));
+                }
+            }
+        }
+    }
+    params
+}
+
+/// Whether `path` matches `pattern`, which may end in a `*` wildcard.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for NewRelic {
+    fn info(&self) -> Info {
+        Info {
+            name: "New Relic instrumentation",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    /// Record that the fairing is attached, so that transaction guards can
+    /// check for it at launch.
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        if rocket.state::<FairingAttached>().is_some() {
+            return Ok(rocket);
+        }
+        Ok(rocket.manage(FairingAttached))
+    }
+
+    /// Store an atomic reference to the app in the request-local cache,
+    /// so that it can be used to create a transaction if required.
+    ///
+    /// If all requests are being instrumented, the transaction is begun
+    /// here instead.
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(RequestReceived::now);
+        let app = self.app_for(request.uri().path().as_str());
+        request.local_cache(|| AppWrapper::App(app.clone(), Arc::clone(&self.config)));
+        if self.config.instrument_all {
+            request.local_cache(|| {
+                Arc::new(Transaction::new(
+                    app,
+                    &self.config,
+                    request,
+                    self.config.transaction_kind(request),
+                ))
+            });
+        }
+    }
+
+    /// End the New Relic transaction(s), if the request has any stored.
+    ///
+    /// Records the response status code in a `response.status_code`
+    /// attribute, the time since a transaction request guard was resolved
+    /// (roughly the handler's run time) in `handler.duration_ms`, how long
+    /// the request waited before then in `request.queue_time_ms`, and adds
+    /// an error to the transaction if the response did not succeed (or
+    /// matched the predicate given to `NewRelicBuilder::error_when`, or was
+    /// given a class by `NewRelicBuilder::error_class`).
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let transaction = request.local_cache(|| Arc::new(Transaction::None));
+        let background = &request
+            .local_cache(|| BackgroundTransaction(Transaction::None))
+            .0;
+        if matches!(**transaction, Transaction::None) && matches!(background, Transaction::None) {
+            return;
+        }
+        let details = ResponseDetails::new(&self.config, request, response);
+        transaction.finish(&details);
+        background.finish(&details);
+    }
+}
+
+/// The details of a response, and the route which handled it, which are
+/// recorded in its transaction.
+///
+/// These are gathered up front since `Response` isn't `Sync`, so can't be
+/// held while waiting to lock the transaction.
+struct ResponseDetails {
+    route_name: Option<String>,
+    route_attributes: Vec<(String, String)>,
+    ignored: bool,
+    status: Status,
+    /// The message and class of the error to record, if the response is an
+    /// error.
+    error: Option<(String, String)>,
+    content_length: Option<usize>,
+    /// How long the request waited before the handler began, if it used a
+    /// transaction request guard.
+    queue_time: Option<Duration>,
+    /// How long the handler took, if it used a transaction request guard.
+    handler_duration: Option<Duration>,
+    /// The captured response headers, as attributes.
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseDetails {
+    fn new(config: &Config, request: &Request<'_>, response: &Response<'_>) -> Self {
+        let content_length = response.body().preset_size().or_else(|| {
+            response
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|l| l.parse().ok())
+        });
+        let mut route_attributes = request
+            .route()
+            .map_or_else(Vec::new, |r| config.route_attributes(r, request));
+        config.prepare_attributes(&mut route_attributes);
+        let mut headers: Vec<_> = config
+            .captured_response_headers
+            .iter()
+            .filter_map(|name| {
+                let value = response.headers().get_one(name)?;
+                Some((format!("response.header.{}", name), value.to_string()))
+            })
+            .collect();
+        config.prepare_attributes(&mut headers);
+        Self {
+            route_name: RequestName::get(request).or_else(|| {
+                request.route().map_or_else(
+                    || config.unrouted_name(request),
+                    |r| Some(config.transaction_name(r, request)),
+                )
+            }),
+            route_attributes,
+            ignored: request.route().map_or_else(
+                || config.unrouted == UnroutedBehavior::Skip,
+                |r| config.is_ignored(r),
+            ),
+            status: response.status(),
+            error: config
+                .error_class(response.status())
+                .map(|class| ((config.error_message)(request, response), class)),
+            content_length,
+            queue_time: request
+                .local_cache(RequestReceived::now)
+                .queue_time(request),
+            handler_duration: HandlerStarted::elapsed(request),
+            headers,
+        }
+    }
+}
+
+/// A transaction name given to a request using `Transaction::name_request`.
+#[derive(Default)]
+struct RequestName(Mutex<Option<String>>);
+
+impl RequestName {
+    fn get(request: &Request<'_>) -> Option<String> {
+        request
+            .local_cache(RequestName::default)
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Attributes buffered by a transaction until it ends, when they're only
+/// added if it took longer than `NewRelicBuilder::detail_threshold_ms`.
+struct DetailAttributes {
+    started: Instant,
+    attributes: Mutex<Vec<(String, RecordedAttribute)>>,
+}
+
+impl DetailAttributes {
+    fn new(attributes: Vec<(String, String)>) -> Self {
+        Self {
+            started: Instant::now(),
+            attributes: Mutex::new(
+                attributes
+                    .into_iter()
+                    .map(|(key, value)| (key, RecordedAttribute::String(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn buffer(&self, key: &str, attribute: RecordedAttribute) {
+        self.attributes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((key.to_string(), attribute));
+    }
+
+    /// The buffered attributes, if the transaction has been running for at
+    /// least `threshold`.
+    fn take_if_slower(&self, threshold: Duration) -> Vec<(String, RecordedAttribute)> {
+        if self.started.elapsed() < threshold {
+            return Vec::new();
+        }
+        std::mem::take(
+            &mut *self
+                .attributes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+/// When the fairing received a request, used to calculate queue time.
+struct RequestReceived {
+    instant: Instant,
+    time: SystemTime,
+}
+
+impl RequestReceived {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            time: SystemTime::now(),
+        }
+    }
+
+    /// How long the request waited before its handler began, if a
+    /// transaction request guard was resolved to mark the handler starting.
+    ///
+    /// If the request has an `X-Request-Start` header, as added by many load
+    /// balancers and proxies, this is measured from the header's timestamp.
+    /// Otherwise it's measured from the request reaching the fairing.
+    fn queue_time(&self, request: &Request<'_>) -> Option<Duration> {
+        let waited = HandlerStarted::instant(request)?.saturating_duration_since(self.instant);
+        match request
+            .headers()
+            .get_one("X-Request-Start")
+            .and_then(parse_request_start)
+        {
+            Some(start) => Some(
+                (self.time + waited)
+                    .duration_since(start)
+                    .unwrap_or_default(),
+            ),
+            None => Some(waited),
+        }
+    }
+}
+
+/// When a transaction request guard was first resolved, which is just
+/// before the handler runs, used to calculate how long the handler took.
+struct HandlerStarted(Option<Instant>);
+
+impl HandlerStarted {
+    fn now() -> Self {
+        Self(Some(Instant::now()))
+    }
+
+    /// Record that the handler has started, if it hasn't already.
+    fn record(request: &Request<'_>) {
+        request.local_cache(Self::now);
+    }
+
+    /// When the handler started, if a transaction request guard was
+    /// resolved.
+    fn instant(request: &Request<'_>) -> Option<Instant> {
+        request.local_cache(|| Self(None)).0
+    }
+
+    /// How long the handler has been running, if a transaction request
+    /// guard was resolved.
+    fn elapsed(request: &Request<'_>) -> Option<Duration> {
+        Self::instant(request).map(|i| i.elapsed())
+    }
+}
+
+/// Parse the timestamp of an `X-Request-Start` header.
+///
+/// The value may be prefixed with `t=`, and may be in seconds, milliseconds
+/// or microseconds since the Unix epoch; the unit is inferred from the
+/// magnitude.
+fn parse_request_start(value: &str) -> Option<SystemTime> {
+    let timestamp: f64 = value.trim().trim_start_matches("t=").parse().ok()?;
+    let seconds = if timestamp > 1e15 {
+        timestamp / 1e6
+    } else if timestamp > 1e12 {
+        timestamp / 1e3
+    } else {
+        timestamp
+    };
+    let since_epoch = Duration::try_from_secs_f64(seconds).ok()?;
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+/// This is used to pass the app, and the options used to instrument
+/// requests, into the request-local cache.
+///
+/// We need to use an `Arc<App>` here because the request-local cache requires
+/// that any references are `'static` (see
+/// [Rocket issue #1005](https://github.com/SergioBenitez/Rocket/issues/1005)).
+/// `App` isn't Clone or Copy since it contains a raw pointer to some C memory
+/// so we reference-count instead.
+enum AppWrapper {
+    App(AppHandle, Arc<Config>),
+    None,
+}
+
+/// The app used to begin transactions: either a New Relic SDK app, or a
+/// recorder for a mock fairing.
+#[derive(Clone)]
+enum AppHandle {
+    Sdk(Arc<newrelic::App>),
+    Mock(MockRecorder),
+}
+
+/// This has to be public since it's used inside the Transaction enum,
+/// but it serves no purpose to users (since its inner field is private).
+///
+/// A `RwLock` rather than a `Mutex` is used since the C SDK synchronizes
+/// access to each transaction internally: every SDK call which reads or
+/// modifies a transaction (adding attributes, starting and ending segments,
+/// noticing errors etc.) takes the transaction's own mutex. Concurrent
+/// `&newrelic::Transaction` access under read guards is therefore safe. The
+/// only operation which needs exclusive access is ending the transaction,
+/// which frees it. The fairing only ends the transaction if it can take the
+/// write lock straight away; otherwise it's ended when the last reference
+/// to it is dropped, so that the response isn't held up by detached tasks.
+///
+/// An async-aware `RwLock` is used so that the read guard can be held
+/// across `.await` points by the async segment methods while keeping the
+/// handler's future `Send`. The synchronous methods use `try_read`, which
+/// only fails once `on_response` has begun ending the transaction.
+///
+/// The `OwnedTransaction` docs include an example of using a transaction
+/// from several tasks at once.
+#[doc(hidden)]
+pub struct InnerTransaction {
+    /// The lock is reference counted so that a `SegmentHandle` can own its
+    /// read guard.
+    transaction: Arc<RwLock<newrelic::Transaction>>,
+    config: Arc<Config>,
+    /// The name the transaction was started with.
+    name: String,
+    /// Whether the transaction has been explicitly renamed using
+    /// `Transaction::set_name`.
+    renamed: AtomicBool,
+    /// Whether the transaction has been discarded using
+    /// `Transaction::ignore`.
+    ignored: AtomicBool,
+    /// Whether the fairing has ended the transaction.
+    ended: AtomicBool,
+    /// Whether the error responded with has already been recorded, e.g. by
+    /// `TracedResult`, so the fairing shouldn't record it again.
+    response_error_noticed: AtomicBool,
+    /// How many segments created through `Transaction` are currently open.
+    open_segments: AtomicUsize,
+    /// Attributes held back until the transaction is known to be slow.
+    details: DetailAttributes,
+}
+
+impl InnerTransaction {
+    /// Handle failing to lock the transaction, as configured by
+    /// `NewRelicBuilder::on_lock_failure`.
+    fn lock_failed(&self, e: &TryLockError) {
+        match self.config.lock_failure {
+            LockFailure::Warn => warn!("Error locking transaction RwLock: {}", e),
+            LockFailure::Panic => panic!("Error locking transaction RwLock: {}", e),
+            LockFailure::Silent => debug!("Error locking transaction RwLock: {}", e),
+        }
+    }
+
+    /// Lock the transaction for use, unless it has already ended.
+    ///
+    /// Work done after the response has been sent (e.g. by spawned tasks)
+    /// is discarded quietly, since the SDK would reject it anyway.
+    fn try_read(&self) -> Option<RwLockReadGuard<'_, newrelic::Transaction>> {
+        if self.has_ended() {
+            return None;
+        }
+        match self.transaction.try_read() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                self.lock_failed(&e);
+                None
+            }
+        }
+    }
+
+    /// Lock the transaction for use, unless it has already ended, returning
+    /// a guard which keeps the transaction alive by itself.
+    fn try_read_owned(&self) -> Option<OwnedRwLockReadGuard<newrelic::Transaction>> {
+        if self.has_ended() {
+            return None;
+        }
+        match Arc::clone(&self.transaction).try_read_owned() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                self.lock_failed(&e);
+                None
+            }
+        }
+    }
+
+    /// Lock the transaction for use, waiting for the lock, unless it has
+    /// already ended.
+    async fn read(&self) -> Option<RwLockReadGuard<'_, newrelic::Transaction>> {
+        let t = self.transaction.read().await;
+        if self.has_ended() {
+            return None;
+        }
+        Some(t)
+    }
+
+    /// Whether the fairing has ended the transaction, after which it
+    /// shouldn't be used.
+    fn has_ended(&self) -> bool {
+        let ended = self.ended.load(Ordering::Relaxed);
+        if ended {
+            debug!("Not using New Relic transaction which has already ended");
+        }
+        ended
+    }
+
+    /// Record details of the response in the locked transaction.
+    ///
+    /// Returns whether the transaction should be ended, rather than having
+    /// been ignored.
+    fn record_response(&self, t: &newrelic::Transaction, response: &ResponseDetails) -> bool {
+        // Transactions begun before routing may turn out to be for an
+        // ignored route, or the handler may have discarded them.
+        if response.ignored || self.ignored.load(Ordering::Relaxed) {
+            if let Err(e) = t.ignore() {
+                warn!("Could not ignore transaction: {}", e);
+            }
+            return false;
+        }
+        // If the request was forwarded to another route after the
+        // transaction began, name the transaction after the route which
+        // actually handled it (unless it was explicitly renamed).
+        if let Some(route_name) = &response.route_name {
+            if *route_name != self.name {
+                if !self.renamed.load(Ordering::Relaxed) {
+                    if let Err(e) = t.name(route_name) {
+                        warn!("Could not rename transaction: {}", e);
+                    }
+                }
+                for (key, value) in &response.route_attributes {
+                    if let Err(e) = t.add_attribute(key, value.as_str()) {
+                        warn!("Could not add {} attribute to transaction: {}", key, e);
+                    }
+                }
+            }
+        }
+        // Add the detailed attributes, if the transaction was slow
+        // enough to keep them
+        if let Some(threshold) = self.config.detail_threshold {
+            for (key, value) in self.details.take_if_slower(threshold) {
+                if let Err(e) = t.add_attribute(&key, value.as_attribute()) {
+                    warn!("Could not add {} attribute to transaction: {}", key, e);
+                }
+            }
+        }
+        if let Some(queue_time) = response.queue_time {
+            if let Err(e) = t.add_attribute("request.queue_time_ms", duration_ms(queue_time)) {
+                warn!("Could not add queue time attribute to transaction: {}", e);
+            }
+        }
+        if let Some(duration) = response.handler_duration {
+            if let Err(e) = t.add_attribute("handler.duration_ms", duration_ms(duration)) {
+                warn!(
+                    "Could not add handler duration attribute to transaction: {}",
+                    e
+                );
+            }
+        }
+        // Record the size of the response body, if it's known up front
+        if let Some(length) = response.content_length.and_then(|l| i64::try_from(l).ok()) {
+            if let Err(e) = t.add_attribute("response.content_length", length) {
+                warn!(
+                    "Could not add content length attribute to transaction: {}",
+                    e
+                );
+            }
+        }
+        for (key, value) in &response.headers {
+            if let Err(e) = t.add_attribute(key, value.as_str()) {
+                warn!("Could not add {} attribute to transaction: {}", key, e);
+            }
+        }
+        // Record the status, and any errors
+        let status = response.status;
+        if let Err(e) = t.add_attribute("response.status_code", i32::from(status.code)) {
+            warn!("Could not add status code attribute to transaction: {}", e);
+        }
+        if let Some((message, class)) = &response.error {
+            if !self.response_error_noticed.load(Ordering::Relaxed) {
+                if let Err(msg) = t.notice_error(i32::from(status.code), message, class) {
+                    warn!("Could not add error to New Relic transaction: {}", msg);
+                }
+            }
+        }
+        true
+    }
+
+    /// Add an attribute to the locked transaction, redacting it if required.
+    fn add_attribute(&self, t: &newrelic::Transaction, key: &str, attribute: newrelic::Attribute) {
+        let attribute = self.config.redact(key, attribute);
+        let truncated = self.config.truncate_attribute(&attribute);
+        let attribute = truncated
+            .as_ref()
+            .map_or(attribute, newrelic::Attribute::OwnedString);
+        if self.config.detail_threshold.is_some() {
+            self.details.buffer(key, attribute.into());
+            return;
+        }
+        match t.add_attribute(key, attribute) {
+            Ok(_) => debug!("Successfully added attribute"),
+            Err(e) => debug!("Could not add attribute to transaction: {}", e),
+        };
+    }
+}
+
+/// Records of the transactions instrumented by a mock [`NewRelic`] fairing.
+///
+/// Created using [`NewRelic::mock`] or [`NewRelicBuilder::build_mock`].
+/// Clones share the same records.
+#[derive(Clone, Debug, Default)]
+pub struct MockRecorder(Arc<Mutex<Vec<RecordedTransaction>>>);
+
+impl MockRecorder {
+    /// The transactions which have ended so far, in the order they ended.
+    pub fn transactions(&self) -> Vec<RecordedTransaction> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Remove all recorded transactions.
+    pub fn clear(&self) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    fn record(&self, transaction: RecordedTransaction) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(transaction);
+    }
+}
+
+/// A transaction recorded by a mock [`NewRelic`] fairing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedTransaction {
+    /// The final name of the transaction.
+    pub name: String,
+    /// Whether this was a web transaction, rather than a non-web
+    /// ([`BackgroundTransaction`]) one.
+    pub web: bool,
+    /// The attributes added to the transaction, in the order they were
+    /// added, including those added automatically.
+    pub attributes: Vec<(String, RecordedAttribute)>,
+    /// The segments created in the transaction, in the order they were
+    /// created.
+    pub segments: Vec<RecordedSegment>,
+    /// The errors recorded in the transaction.
+    pub errors: Vec<RecordedError>,
+    /// The custom events recorded in the transaction.
+    pub events: Vec<RecordedEvent>,
+    /// The names and values of the custom metrics recorded in the
+    /// transaction.
+    pub metrics: Vec<(String, f64)>,
+}
+
+impl RecordedTransaction {
+    /// The value of the attribute with the given key, if one was added.
+    ///
+    /// If the attribute was added more than once, this is the last value
+    /// added, which is the one New Relic keeps.
+    pub fn attribute(&self, key: &str) -> Option<&RecordedAttribute> {
+        self.attributes
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The value of an attribute recorded by a mock [`NewRelic`] fairing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordedAttribute {
+    /// A short (i32) integer attribute.
+    Int(i32),
+    /// A long (i64) integer attribute.
+    Long(i64),
+    /// A float (f64) attribute.
+    Float(f64),
+    /// A string attribute.
+    String(String),
+}
+
+impl RecordedAttribute {
+    fn as_attribute(&self) -> newrelic::Attribute<'_> {
+        match self {
+            Self::Int(i) => newrelic::Attribute::Int(*i),
+            Self::Long(l) => newrelic::Attribute::Long(*l),
+            Self::Float(f) => newrelic::Attribute::Float(*f),
+            Self::String(s) => newrelic::Attribute::String(s),
+        }
+    }
+}
+
+impl From<newrelic::Attribute<'_>> for RecordedAttribute {
+    fn from(attribute: newrelic::Attribute<'_>) -> Self {
+        match attribute {
+            newrelic::Attribute::Int(i) => Self::Int(i),
+            newrelic::Attribute::Long(l) => Self::Long(l),
+            newrelic::Attribute::Float(f) => Self::Float(f),
+            newrelic::Attribute::String(s) => Self::String(s.to_string()),
+            newrelic::Attribute::OwnedString(s) => Self::String(s.clone()),
+        }
+    }
+}
+
+/// A custom event recorded by a mock [`NewRelic`] fairing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedEvent {
+    /// The type of the event.
+    pub event_type: String,
+    /// The attributes of the event.
+    pub attributes: Vec<(String, RecordedAttribute)>,
+}
+
+/// A segment recorded by a mock [`NewRelic`] fairing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordedSegment {
+    /// A custom segment.
+    Custom {
+        /// The name of the segment.
+        name: String,
+        /// The category of the segment.
+        category: String,
+    },
+    /// A datastore segment.
+    Datastore {
+        /// The datastore product, e.g. `"Postgres"`.
+        product: &'static str,
+        /// The table or collection queried.
+        table: String,
+        /// The operation performed.
+        operation: String,
+        /// The query executed.
+        sql: String,
+    },
+    /// A datastore segment created from prebuilt parameters using
+    /// `Transaction::datastore_segment_with`, whose details can't be
+    /// inspected.
+    PrebuiltDatastore,
+    /// An external segment.
+    External {
+        /// The host or URI called.
+        host: String,
+        /// The procedure of the call, if given.
+        procedure: Option<String>,
+        /// The library used to make the call, if given.
+        library: Option<String>,
+    },
+}
+
+/// An error recorded by a mock [`NewRelic`] fairing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedError {
+    /// The error priority; for HTTP errors, the response status code.
+    pub code: i32,
+    /// The error message.
+    pub message: String,
+    /// The error class.
+    pub class: String,
+}
+
+/// This has to be public since it's used inside the Transaction enum,
+/// but it serves no purpose to users (since its inner fields are private).
+///
+/// A mock transaction applies the same configuration (redaction, segment
+/// name sanitization, error predicate etc.) as a real one, and adds itself
+/// to the recorder when the request has completed.
+#[doc(hidden)]
+pub struct MockTransaction {
+    recorder: MockRecorder,
+    config: Arc<Config>,
+    transaction: Mutex<RecordedTransaction>,
+    /// The name the transaction was started with.
+    name: String,
+    /// Whether the transaction has been explicitly renamed using
+    /// `Transaction::set_name`.
+    renamed: AtomicBool,
+    /// Whether the transaction has been discarded using
+    /// `Transaction::ignore`.
+    ignored: AtomicBool,
+    /// Whether the fairing has finished the transaction.
+    finished: AtomicBool,
+    /// Whether the error responded with has already been recorded, e.g. by
+    /// `TracedResult`, so the fairing shouldn't record it again.
+    response_error_noticed: AtomicBool,
+    /// How many segments created through `Transaction` are currently open.
+    open_segments: AtomicUsize,
+    /// Attributes held back until the transaction is known to be slow.
+    details: DetailAttributes,
+}
+
+impl MockTransaction {
+    fn new(
+        recorder: &MockRecorder,
+        config: &Arc<Config>,
+        name: String,
+        kind: TransactionKind,
+        attributes: Vec<(String, String)>,
+        details: DetailAttributes,
+    ) -> Self {
+        debug!("Began mock New Relic transaction");
+        let transaction = RecordedTransaction {
+            name: name.clone(),
+            web: matches!(kind, TransactionKind::Web),
+            attributes: attributes
+                .into_iter()
+                .map(|(key, value)| (key, RecordedAttribute::String(value)))
+                .collect(),
+            segments: Vec::new(),
+            errors: Vec::new(),
+            events: Vec::new(),
+            metrics: Vec::new(),
+        };
+        Self {
+            recorder: recorder.clone(),
+            config: Arc::clone(config),
+            transaction: Mutex::new(transaction),
+            name,
+            renamed: AtomicBool::new(false),
+            ignored: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            response_error_noticed: AtomicBool::new(false),
+            open_segments: AtomicUsize::new(0),
+            details,
+        }
+    }
+
+    fn update<F: FnOnce(&mut RecordedTransaction)>(&self, func: F) {
+        func(
+            &mut self
+                .transaction
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+
+    fn add_attribute(&self, key: &str, attribute: newrelic::Attribute) {
+        let attribute = self.config.redact(key, attribute);
+        let attribute = match self.config.truncate_attribute(&attribute) {
+            Some(truncated) => RecordedAttribute::String(truncated),
+            None => attribute.into(),
+        };
+        if self.config.detail_threshold.is_some() {
+            self.details.buffer(key, attribute);
+            return;
+        }
+        self.update(|t| t.attributes.push((key.to_string(), attribute)));
+    }
+
+    fn record_metric(&self, name: &str, value: f64) {
+        self.update(|t| t.metrics.push((name.to_string(), value)));
+    }
+
+    fn record_custom_event(&self, event_type: &str, attributes: &[(&str, newrelic::Attribute)]) {
+        let event = RecordedEvent {
+            event_type: event_type.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(key, attribute)| {
+                    let attribute = self.config.redact(key, copy_attribute(attribute));
+                    (key.to_string(), attribute.into())
+                })
+                .collect(),
+        };
+        self.update(|t| t.events.push(event));
+    }
+
+    fn set_name(&self, name: &str) {
+        self.renamed.store(true, Ordering::Relaxed);
+        self.update(|t| t.name = name.to_string());
+    }
+
+    fn notice_error(&self, code: i32, message: &str, class: &str) {
+        let error = RecordedError {
+            code,
+            message: message.to_string(),
+            class: class.to_string(),
+        };
+        self.update(|t| t.errors.push(error));
+    }
+
+    fn custom_segment(&self, name: &str, category: &str) {
+        let segment = RecordedSegment::Custom {
+            name: name.to_string(),
+            category: category.to_string(),
+        };
+        self.update(|t| t.segments.push(segment));
+    }
+
+    fn datastore_segment(
+        &self,
+        datastore: &newrelic::Datastore,
+        table: &str,
+        operation: &str,
+        sql: &str,
+    ) {
+        let segment = RecordedSegment::Datastore {
+            product: datastore_product(datastore),
+            table: self.config.segment_name(table).into_owned(),
+            operation: self.config.segment_name(operation).into_owned(),
+            sql: sql.to_string(),
+        };
+        self.update(|t| t.segments.push(segment));
+    }
+
+    fn prebuilt_datastore_segment(&self) {
+        self.update(|t| t.segments.push(RecordedSegment::PrebuiltDatastore));
+    }
+
+    fn external_segment(&self, host: &str, procedure: Option<&str>, library: Option<&str>) {
+        let segment = RecordedSegment::External {
+            host: host.to_string(),
+            procedure: procedure.map(|p| self.config.segment_name(p).into_owned()),
+            library: library.map(|l| self.config.segment_name(l).into_owned()),
+        };
+        self.update(|t| t.segments.push(segment));
+    }
+
+    /// Record details of the response, then add the transaction to the
+    /// recorder.
+    fn finish(&self, response: &ResponseDetails) {
+        self.finished.store(true, Ordering::Relaxed);
+        if response.ignored || self.ignored.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut t = self
+            .transaction
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Some(route_name) = &response.route_name {
+            if *route_name != self.name {
+                if !self.renamed.load(Ordering::Relaxed) {
+                    t.name = route_name.clone();
+                }
+                for (key, value) in &response.route_attributes {
+                    t.attributes
+                        .push((key.clone(), RecordedAttribute::String(value.clone())));
+                }
+            }
+        }
+        if let Some(threshold) = self.config.detail_threshold {
+            t.attributes.extend(self.details.take_if_slower(threshold));
+        }
+        if let Some(queue_time) = response.queue_time {
+            t.attributes.push((
+                "request.queue_time_ms".to_string(),
+                RecordedAttribute::Long(duration_ms(queue_time)),
+            ));
+        }
+        if let Some(duration) = response.handler_duration {
+            t.attributes.push((
+                "handler.duration_ms".to_string(),
+                RecordedAttribute::Long(duration_ms(duration)),
+            ));
+        }
+        if let Some(length) = response.content_length.and_then(|l| i64::try_from(l).ok()) {
+            t.attributes.push((
+                "response.content_length".to_string(),
+                RecordedAttribute::Long(length),
+            ));
+        }
+        for (key, value) in &response.headers {
+            t.attributes
+                .push((key.clone(), RecordedAttribute::String(value.clone())));
+        }
+        let status = response.status;
+        t.attributes.push((
+            "response.status_code".to_string(),
+            RecordedAttribute::Int(i32::from(status.code)),
+        ));
+        if let Some((message, class)) = response
+            .error
+            .as_ref()
+            .filter(|_| !self.response_error_noticed.load(Ordering::Relaxed))
+        {
+            t.errors.push(RecordedError {
+                code: i32::from(status.code),
+                message: message.clone(),
+                class: class.clone(),
+            });
+        }
+        self.recorder.record(t);
+    }
+}
+
+/// Record transactions whose requests were dropped before the response was
+/// sent, as a real transaction is ended when it's dropped.
+impl Drop for MockTransaction {
+    fn drop(&mut self) {
+        if self.finished.load(Ordering::Relaxed) || self.ignored.load(Ordering::Relaxed) {
+            return;
+        }
+        let t = self
+            .transaction
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.recorder.record(t.clone());
+    }
+}
+
+/// Copy an attribute, since `newrelic::Attribute` isn't `Clone`.
+// Clippy doesn't notice that the type isn't `Copy`.
+#[allow(clippy::needless_match)]
+fn copy_attribute<'a>(attribute: &newrelic::Attribute<'a>) -> newrelic::Attribute<'a> {
+    match *attribute {
+        newrelic::Attribute::Int(i) => newrelic::Attribute::Int(i),
+        newrelic::Attribute::Long(l) => newrelic::Attribute::Long(l),
+        newrelic::Attribute::Float(f) => newrelic::Attribute::Float(f),
+        newrelic::Attribute::String(s) => newrelic::Attribute::String(s),
+        newrelic::Attribute::OwnedString(s) => newrelic::Attribute::OwnedString(s),
+    }
+}
+
+/// The name the New Relic SDK uses for a datastore product.
+fn datastore_product(datastore: &newrelic::Datastore) -> &'static str {
+    match datastore {
+        newrelic::Datastore::Firebird => "Firebird",
+        newrelic::Datastore::Informix => "Informix",
+        newrelic::Datastore::MSSQL => "MSSQL",
+        newrelic::Datastore::MySQL => "MySQL",
+        newrelic::Datastore::Oracle => "Oracle",
+        newrelic::Datastore::Postgres => "Postgres",
+        newrelic::Datastore::SQLite => "SQLite",
+        newrelic::Datastore::Sybase => "Sybase",
+        newrelic::Datastore::Memcached => "Memcached",
+        newrelic::Datastore::MongoDB => "MongoDB",
+        newrelic::Datastore::ODBC => "ODBC",
+        newrelic::Datastore::Redis => "Redis",
+        newrelic::Datastore::Other => "Other",
+    }
+}
+
+/// A New Relic transaction.
+///
+/// When included in a request guard, this transaction will trace
+/// the request in New Relic. Custom attributes and segments can be
+/// added using the various methods of this enum.
+///
+/// Note that if an error is encountered when registering the transaction
+/// with the New Relic SDK then this could be the `Transaction::None`
+/// variant, indicating that the request is not being instrumented.
+/// In this case a warning message will be logged.
+///
+/// More variants may be added in future, so matches on a transaction need a
+/// wildcard arm.
+#[non_exhaustive]
+pub enum Transaction {
+    /// A running New Relic transaction.
+    Running(InnerTransaction),
+
+    /// A transaction recorded in memory by a mock fairing.
+    ///
+    /// See [`NewRelic::mock`].
+    Mock(MockTransaction),
+
+    /// A dummy transaction; used if the New Relic SDK
+    /// returns an error.
+    None,
+}
+
+/// The default transaction name for requests handled by `route`, mounted
+/// at `base`.
+///
+/// This is the base (without the leading slash) followed by the handler
+/// name.
+fn route_transaction_name(base: &str, route: &Route) -> String {
+    let name = route.name.as_deref().unwrap_or("unknown_handler");
+    // Collapse repeated slashes and strip leading and trailing ones, which
+    // can be left by bases such as `/` or handlers with empty names.
+    let name = base
+        .split('/')
+        .chain(name.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    if name.is_empty() {
+        "unknown_handler".to_string()
+    } else {
+        name
+    }
+}
+
+/// A duration in whole milliseconds, for use as an attribute value.
+fn duration_ms(duration: Duration) -> i64 {
+    i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// A New Relic non-web (background) transaction.
+///
+/// This can be used as a request guard instead of [`Transaction`] for
+/// routes which are conceptually background jobs rather than web requests,
+/// so that they appear under New Relic's non-web transactions. All of the
+/// methods of [`Transaction`] are available through `Deref`.
+pub struct BackgroundTransaction(Transaction);
+
+impl Deref for BackgroundTransaction {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// An owned handle to the request's [`Transaction`].
+///
+/// This can be used as a request guard instead of `&Transaction` when the
+/// transaction needs to outlive the borrow of the request, e.g. to be moved
+/// into a task spawned by the handler. All of the methods of [`Transaction`]
+/// are available through `Deref`.
+///
+/// The transaction is still finished once the response has been sent, after
+/// which anything recorded using the handle is discarded. The response
+/// isn't delayed by tasks which still have segments open: the transaction is
+/// then ended once they've completed and the last handle is dropped.
+///
+/// ```rust
+/// use rocket::local::blocking::Client;
+/// use rocket_newrelic::{NewRelic, OwnedTransaction};
+///
+/// #[rocket::post("/reports")]
+/// async fn create_report(transaction: OwnedTransaction) -> &'static str {
+///     let task = rocket::tokio::spawn(async move {
+///         transaction.custom_segment("build report", "reports", |_| ());
+///     });
+///     task.await.expect("task succeeded");
+///     "Created"
+/// }
+///
+/// let (newrelic, recorder) = NewRelic::mock();
+/// let rocket = rocket::build()
+///     .attach(newrelic)
+///     .mount("/", rocket::routes![create_report]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// client.post("/reports").dispatch();
+///
+/// assert_eq!(recorder.transactions()[0].segments.len(), 1);
+/// ```
+///
+/// For example, the response here is sent while the task is still working:
+///
+/// ```rust
+/// use std::sync::Arc;
+///
+/// use rocket::{local::blocking::Client, tokio::sync::Notify, State};
+/// use rocket_newrelic::{NewRelic, OwnedTransaction};
+///
+/// #[rocket::post("/imports")]
+/// fn start_import(transaction: OwnedTransaction, done: &State<Arc<Notify>>) -> &'static str {
+///     let done = Arc::clone(done);
+///     rocket::tokio::spawn(async move {
+///         transaction
+///             .custom_segment_async("import", "jobs", || done.notified())
+///             .await;
+///     });
+///     "Importing"
+/// }
+///
+/// let done = Arc::new(Notify::new());
+/// let (newrelic, recorder) = NewRelic::mock();
+/// let rocket = rocket::build()
+///     .attach(newrelic)
+///     .manage(Arc::clone(&done))
+///     .mount("/", rocket::routes![start_import]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// let response = client.post("/imports").dispatch();
+/// assert_eq!(response.into_string().as_deref(), Some("Importing"));
+/// assert_eq!(recorder.transactions().len(), 1);
+/// done.notify_one();
+/// ```
+///
+/// Clones of the handle can be used from several tasks at once, since
+/// segments and attributes only need shared access to the transaction:
+///
+/// ```rust
+/// use rocket::local::blocking::Client;
+/// use rocket_newrelic::{NewRelic, OwnedTransaction};
+///
+/// #[rocket::get("/fan-out")]
+/// async fn fan_out(transaction: OwnedTransaction) -> &'static str {
+///     let tasks: Vec<_> = (0..8)
+///         .map(|i| {
+///             let transaction = transaction.clone();
+///             rocket::tokio::spawn(async move {
+///                 for _ in 0..10 {
+///                     transaction.custom_segment("work", "tasks", |_| ());
+///                     transaction
+///                         .custom_segment_async("io", "tasks", || rocket::tokio::task::yield_now())
+///                         .await;
+///                 }
+///                 transaction.add_attribute(&format!("task.{}", i), i);
+///             })
+///         })
+///         .collect();
+///     for task in tasks {
+///         task.await.expect("task succeeded");
+///     }
+///     "Done"
+/// }
+///
+/// let (newrelic, recorder) = NewRelic::mock();
+/// let rocket = rocket::build()
+///     .attach(newrelic)
+///     .mount("/", rocket::routes![fan_out]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// client.get("/fan-out").dispatch();
+///
+/// let transaction = &recorder.transactions()[0];
+/// assert_eq!(transaction.segments.len(), 160);
+/// assert!((0..8).all(|i| transaction.attribute(&format!("task.{}", i)).is_some()));
+/// ```
+#[derive(Clone)]
+pub struct OwnedTransaction(Arc<Transaction>);
+
+impl Deref for OwnedTransaction {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A request guard giving access to the New Relic app used to instrument the
+/// request, e.g. to begin transactions which aren't tied to the request.
+///
+/// ```rust
+/// use rocket_newrelic::AppRef;
+///
+/// #[rocket::post("/cleanup")]
+/// fn cleanup(app: AppRef<'_>) -> &'static str {
+///     if let Some(app) = app.get() {
+///         let _transaction = app.non_web_transaction("cleanup");
+///         // ...
+///     }
+///     "Cleaned up"
+/// }
+/// ```
+#[derive(Clone, Copy)]
+pub struct AppRef<'a>(Option<&'a newrelic::App>);
+
+impl<'a> AppRef<'a> {
+    /// The New Relic app, or `None` for a mock fairing or if the fairing
+    /// isn't attached.
+    pub fn get(&self) -> Option<&'a newrelic::App> {
+        self.0
+    }
+}
+
+/// A custom segment which is ended when dropped, created using
+/// [`Transaction::start_custom_segment`].
+///
+/// Nested segments can be created using the methods of `newrelic::Segment`,
+/// through `Deref`.
+pub struct SegmentHandle<'a> {
+    segment: newrelic::Segment<'a>,
+    _guard: Option<OwnedRwLockReadGuard<newrelic::Transaction>>,
+    _open: Option<OpenSegment<'a>>,
+}
+
+impl<'a> SegmentHandle<'a> {
+    /// A handle for a transaction which isn't running.
+    fn none() -> Self {
+        Self {
+            segment: newrelic::Segment::default(),
+            _guard: None,
+            _open: None,
+        }
+    }
+
+    /// End the segment.
+    ///
+    /// This is equivalent to dropping the handle.
+    pub fn end(self) {}
+}
+
+impl<'a> Drop for SegmentHandle<'a> {
+    fn drop(&mut self) {
+        // End the segment now, while the transaction is still locked, rather
+        // than relying on the order the fields are dropped in.
+        drop(std::mem::take(&mut self.segment));
+    }
+}
+
+/// A transaction which is locked for the duration of
+/// [`Transaction::with_locked`].
+///
+/// Segments created using these methods share a single acquisition of the
+/// transaction's lock. See the methods of the same name on `Transaction` for
+/// details.
+pub struct LockedTransaction<'a> {
+    transaction: &'a Transaction,
+    guard: Option<RwLockReadGuard<'a, newrelic::Transaction>>,
+}
+
+impl<'a> LockedTransaction<'a> {
+    /// Execute the function in a named custom segment.
+    ///
+    /// See [`Transaction::custom_segment`].
+    pub fn custom_segment<F, V>(&self, name: &str, category: &str, func: F) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let _open = match self.transaction.enter_segment() {
+            Some(open) => open,
+            None => return func(newrelic::Segment::default()),
+        };
+        match (self.transaction, &self.guard) {
+            (Transaction::Running(_), Some(t)) => t.custom_segment(name, category, func),
+            (Transaction::Mock(mock), _) => {
+                mock.custom_segment(name, category);
+                func(newrelic::Segment::default())
+            }
+            _ => func(newrelic::Segment::default()),
+        }
+    }
+
+    /// Execute the function in a datastore segment.
+    ///
+    /// See [`Transaction::datastore_segment`].
+    pub fn datastore_segment<O, F, V>(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: O,
+        sql: &str,
+        func: F,
+    ) -> V
+    where
+        O: Into<Operation>,
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let _open = match self.transaction.enter_segment() {
+            Some(open) => open,
+            None => return func(newrelic::Segment::default()),
+        };
+        let operation = operation.into();
+        let operation = operation.as_str();
+        match (self.transaction, &self.guard) {
+            (Transaction::Running(inner), Some(t)) => {
+                let params = inner
+                    .config
+                    .datastore_params(datastore, table, operation, sql);
+                match params {
+                    Ok(p) => t.datastore_segment(&p, func),
+                    Err(e) => {
+                        warn!("Error building datastore parameters: {}", e);
+                        func(newrelic::Segment::default())
+                    }
+                }
+            }
+            (Transaction::Mock(mock), _) => {
+                mock.datastore_segment(&datastore, table, operation, sql);
+                func(newrelic::Segment::default())
+            }
+            _ => func(newrelic::Segment::default()),
+        }
+    }
+
+    /// Execute the function in a datastore segment, using parameters which
+    /// have already been built.
+    ///
+    /// See [`Transaction::datastore_segment_with`].
+    pub fn datastore_segment_with<F, V>(&self, params: &newrelic::DatastoreParams, func: F) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let _open = match self.transaction.enter_segment() {
+            Some(open) => open,
+            None => return func(newrelic::Segment::default()),
+        };
+        match (self.transaction, &self.guard) {
+            (Transaction::Running(_), Some(t)) => t.datastore_segment(params, func),
+            (Transaction::Mock(mock), _) => {
+                mock.prebuilt_datastore_segment();
+                func(newrelic::Segment::default())
+            }
+            _ => func(newrelic::Segment::default()),
+        }
+    }
+
+    /// Execute a function using a pooled database connection in a datastore
+    /// segment.
+    ///
+    /// See [`Transaction::pooled_segment`].
+    pub fn pooled_segment<O, C, F, V>(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: O,
+        conn: C,
+        func: F,
+    ) -> V
+    where
+        O: Into<Operation>,
+        F: FnOnce(C) -> V,
+    {
+        self.datastore_segment(datastore, table, operation, "", |_| func(conn))
+    }
+
+    /// Execute a function in an external segment.
+    ///
+    /// See [`Transaction::external_segment`].
+    pub fn external_segment<F, V>(
+        &self,
+        host: &str,
+        procedure: Option<&str>,
+        library: Option<&str>,
+        func: F,
+    ) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let _open = match self.transaction.enter_segment() {
+            Some(open) => open,
+            None => return func(newrelic::Segment::default()),
+        };
+        match (self.transaction, &self.guard) {
+            (Transaction::Running(inner), Some(t)) => {
+                let procedure = procedure.map(|p| inner.config.segment_name(p));
+                let library = library.map(|l| inner.config.segment_name(l));
+                let mut params = newrelic::ExternalParamsBuilder::new(host);
+                if let Some(p) = &procedure {
+                    params = params.procedure(p);
+                }
+                if let Some(l) = &library {
+                    params = params.library(l);
+                }
+                match params.build() {
+                    Ok(p) => t.external_segment(&p, func),
+                    Err(e) => {
+                        warn!("Error building external New Relic parameters: {}", e);
+                        func(newrelic::Segment::default())
+                    }
+                }
+            }
+            (Transaction::Mock(mock), _) => {
+                mock.external_segment(host, procedure, library);
+                func(newrelic::Segment::default())
+            }
+            _ => func(newrelic::Segment::default()),
+        }
+    }
+}
+
+/// Counts an open segment towards the transaction's limit on open segments,
+/// until dropped.
+struct OpenSegment<'a>(Option<&'a AtomicUsize>);
+
+impl<'a> Drop for OpenSegment<'a> {
+    fn drop(&mut self) {
+        if let Some(open_segments) = self.0 {
+            open_segments.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a> Deref for SegmentHandle<'a> {
+    type Target = newrelic::Segment<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.segment
+    }
+}
+
+impl Transaction {
+    /// Create a new transaction for a request.
+    ///
+    /// The New Relic transaction will have the URL, request method, route
+    /// path and transaction name attributes set.
+    fn new(
+        app: &AppHandle,
+        config: &Arc<Config>,
+        request: &Request,
+        kind: TransactionKind,
+    ) -> Self {
+        if request.route().is_some_and(|r| config.is_ignored(r)) {
+            debug!("Not instrumenting request to ignored route");
+            return Self::None;
+        }
+        if !config.is_sampled() {
+            return Self::None;
+        }
+        if let Some(methods) = &config.methods {
+            if !methods.contains(&request.method()) {
+                debug!("Not instrumenting {} request", request.method());
+                return Self::None;
+            }
+        }
+
+        // Use the route handler as the transaction name, unless the request
+        // has been given an explicit name.
+        // request.route() is only None if the transaction is begun before
+        // routing, in which case it's renamed once the request is handled.
+        let transaction_name: String = RequestName::get(request).unwrap_or_else(|| {
+            request.route().map_or_else(
+                || "unknown_handler".to_string(),
+                |r| config.transaction_name(r, request),
+            )
+        });
+
+        let mut attributes = config.global_attributes.clone();
+        attributes.extend([
+            ("uri".to_string(), request.uri().to_string()),
+            (
+                "request.method".to_string(),
+                request.method().as_str().to_string(),
+            ),
+        ]);
+        if let Some(route) = request.route() {
+            attributes.extend(config.route_attributes(route, request));
+        }
+        // Attributes describing the request in detail, which are only added
+        // to slow transactions if a detail threshold is configured.
+        let mut details = Vec::new();
+        for name in &config.captured_headers {
+            if let Some(value) = request.headers().get_one(name) {
+                details.push((format!("request.header.{}", name), value.to_string()));
+            }
+        }
+        for name in &config.captured_query_params {
+            if let Some(Ok(value)) = request.query_value::<String>(name) {
+                details.push((format!("request.query.{}", name), value));
+            }
+        }
+        if config.capture_client_ip {
+            if let Some(ip) = request.client_ip() {
+                details.push(("request.client_ip".to_string(), ip.to_string()));
+            }
+        }
+        for name in &config.captured_cookies {
+            if let Some(cookie) = request.cookies().get(name) {
+                details.push((
+                    format!("request.cookie.{}", name),
+                    cookie.value().to_string(),
+                ));
+            }
+        }
+        if config.detail_threshold.is_none() {
+            attributes.append(&mut details);
+        }
+        #[cfg(feature = "tracing")]
+        attributes.extend(self::tracing::span_attributes());
+        config.prepare_attributes(&mut attributes);
+        config.prepare_attributes(&mut details);
+
+        let app = match app {
+            AppHandle::Sdk(app) => app,
+            AppHandle::Mock(recorder) => {
+                return Self::Mock(MockTransaction::new(
+                    recorder,
+                    config,
+                    transaction_name,
+                    kind,
+                    attributes,
+                    DetailAttributes::new(details),
+                ))
+            }
+        };
+        let transaction = match kind {
+            TransactionKind::Web => app.web_transaction(&transaction_name),
+            TransactionKind::NonWeb => app.non_web_transaction(&transaction_name),
+        };
+        transaction.map_or_else(
+            |e| {
+                warn!("Error beginning New Relic transaction: {}", e);
+                Self::None
+            },
+            |transaction| {
+                debug!("Began New Relic transaction");
+                for (key, value) in &attributes {
+                    if let Err(e) = transaction.add_attribute(key, value) {
+                        warn!("Could not add {} attribute to transaction: {}", key, e);
+                    }
+                }
+                Self::Running(InnerTransaction {
+                    transaction: Arc::new(RwLock::new(transaction)),
+                    config: Arc::clone(config),
+                    name: transaction_name,
+                    renamed: AtomicBool::new(false),
+                    ignored: AtomicBool::new(false),
+                    ended: AtomicBool::new(false),
+                    response_error_noticed: AtomicBool::new(false),
+                    open_segments: AtomicUsize::new(0),
+                    details: DetailAttributes::new(details),
+                })
+            },
+        )
+    }
+
+    /// Give the request's transaction an explicit name, rather than naming it
+    /// after the route which handles it.
+    ///
+    /// This can be called from a request guard or fairing, and takes effect
+    /// even if the transaction has already begun. It's useful for routes
+    /// whose names aren't meaningful, such as those built from closures.
+    /// A name set by `Transaction::set_name` still takes precedence.
+    ///
+    /// ```rust
+    /// use rocket::{
+    ///     local::blocking::Client,
+    ///     outcome::Outcome,
+    ///     request::{self, FromRequest, Request},
+    /// };
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// struct Checkout;
+    ///
+    /// #[rocket::async_trait]
+    /// impl<'r> FromRequest<'r> for Checkout {
+    ///     type Error = ();
+    ///
+    ///     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+    ///         Transaction::name_request(request, "checkout");
+    ///         Outcome::Success(Checkout)
+    ///     }
+    /// }
+    ///
+    /// #[rocket::post("/cart/pay")]
+    /// fn pay(_name: Checkout, _transaction: &Transaction) -> &'static str {
+    ///     "Paid"
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![pay]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.post("/cart/pay").dispatch();
+    ///
+    /// assert_eq!(recorder.transactions()[0].name, "checkout");
+    /// ```
+    pub fn name_request(request: &Request<'_>, name: &str) {
+        *request
+            .local_cache(RequestName::default)
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(name.to_string());
+    }
+
+    /// Hand a transaction which was started elsewhere over to the fairing.
+    ///
+    /// The transaction is stored on the request in place of the one the
+    /// fairing would otherwise begin, so it's returned by the `Transaction`
+    /// request guard and ended by the fairing once the response is sent. Its
+    /// name is kept, but the usual attributes are added when it ends.
+    ///
+    /// This must be called before the transaction is first requested, e.g.
+    /// from another fairing's `on_request` (attached after this crate's
+    /// fairing, so that its configuration is used). If the request already
+    /// has a transaction then that one is returned, and the given
+    /// transaction is ended immediately.
+    ///
+    /// ```rust,no_run
+    /// use rocket::fairing::AdHoc;
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// let app = newrelic::App::new("My App", "license-key").unwrap();
+    /// let rocket = rocket::build()
+    ///     .attach(NewRelic::new("My App", "license-key").unwrap())
+    ///     .attach(AdHoc::on_request("Start transaction", move |request, _| {
+    ///         if let Ok(transaction) = app.web_transaction("custom") {
+    ///             Transaction::adopt(request, transaction);
+    ///         }
+    ///         Box::pin(async {})
+    ///     }));
+    /// ```
+    pub fn adopt<'r>(request: &'r Request<'_>, transaction: newrelic::Transaction) -> &'r Self {
+        let mut transaction = Some(transaction);
+        let cached = request.local_cache(|| {
+            let config = match request.local_cache(|| AppWrapper::None) {
+                AppWrapper::App(_, config) => Arc::clone(config),
+                AppWrapper::None => Arc::new(Config::default()),
+            };
+            Arc::new(Self::Running(InnerTransaction {
+                transaction: Arc::new(RwLock::new(transaction.take().expect("only taken once"))),
+                config,
+                name: String::new(),
+                renamed: AtomicBool::new(true),
+                ignored: AtomicBool::new(false),
+                ended: AtomicBool::new(false),
+                response_error_noticed: AtomicBool::new(false),
+                open_segments: AtomicUsize::new(0),
+                details: DetailAttributes::new(Vec::new()),
+            }))
+        });
+        if transaction.is_some() {
+            warn!("Request already has a New Relic transaction, ending the adopted one");
+        }
+        cached
+    }
+
+    /// Record details of the response and end the transaction, if it's
+    /// running.
+    fn finish(&self, response: &ResponseDetails) {
+        if let Self::Mock(mock) = self {
+            mock.finish(response);
+        }
+        if let Self::Running(inner) = self {
+            // Ending the transaction needs exclusive access, but tasks
+            // spawned by the handler may still be using it, e.g. in an async
+            // segment. Rather than delaying the response until they've
+            // finished, the response is recorded under a read lock and the
+            // transaction ends once the last reference to it is dropped.
+            match inner.transaction.try_write() {
+                Ok(mut t) => {
+                    inner.ended.store(true, Ordering::Relaxed);
+                    if inner.record_response(&t, response) {
+                        // End the transaction explicitly here.
+                        // Otherwise it ends after the response has finished
+                        // being sent to the client, when it's dropped.
+                        // `newrelic::Transaction::end` discards the result of
+                        // ending the transaction in the C SDK, so there's no
+                        // failure to report here; the SDK logs it to its own
+                        // log instead.
+                        t.end();
+                    }
+                }
+                Err(_) => match inner.transaction.try_read() {
+                    Ok(t) => {
+                        inner.ended.store(true, Ordering::Relaxed);
+                        inner.record_response(&t, response);
+                        debug!("Transaction is still in use, so will end once it's dropped");
+                    }
+                    Err(e) => inner.lock_failed(&e),
+                },
+            }
+        }
+    }
+
+    /// Add an attribute to the transaction.
+    ///
+    /// If the key has been redacted using
+    /// [`NewRelicBuilder::redact_attribute`], the value is replaced with
+    /// `"[REDACTED]"`.
+    pub fn add_attribute<'a, T>(&self, key: &str, attribute: T)
+    where
+        T: Into<newrelic::Attribute<'a>>,
+    {
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                inner.add_attribute(&t, key, attribute.into());
+            }
+        } else if let Self::Mock(mock) = self {
+            mock.add_attribute(key, attribute.into());
+        }
+    }
+
+    /// Add several attributes to the transaction at once.
+    ///
+    /// This only locks the transaction once, so is more efficient than
+    /// calling `add_attribute` repeatedly. Redacted keys are handled in
+    /// the same way as `add_attribute`.
+    pub fn add_attributes<'a, 'k, I, T>(&self, attributes: I)
+    where
+        I: IntoIterator<Item = (&'k str, T)>,
+        T: Into<newrelic::Attribute<'a>>,
+    {
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                for (key, attribute) in attributes {
+                    inner.add_attribute(&t, key, attribute.into());
+                }
+            }
+        } else if let Self::Mock(mock) = self {
+            for (key, attribute) in attributes {
+                mock.add_attribute(key, attribute.into());
+            }
+        }
+    }
+
+    /// Override the name of the transaction.
+    ///
+    /// By default transactions are named after the route's base and handler
+    /// name; this can be used to group requests under a more meaningful
+    /// name instead, e.g. for dynamic routes.
+    pub fn set_name(&self, name: &str) {
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                match t.name(name) {
+                    Ok(_) => {
+                        inner.renamed.store(true, Ordering::Relaxed);
+                        debug!("Successfully renamed transaction");
+                    }
+                    Err(e) => warn!("Could not rename transaction: {}", e),
+                };
+            }
+        } else if let Self::Mock(mock) = self {
+            mock.set_name(name);
+        }
+    }
+
+    /// Discard the transaction, so that it isn't reported to New Relic.
+    ///
+    /// This can be used for requests which turn out to be uninteresting
+    /// only once they're being handled, e.g. from bots. The transaction
+    /// can still be used as normal, but is ignored instead of being ended
+    /// once the response is sent.
+    pub fn ignore(&self) {
+        if let Self::Running(inner) = self {
+            inner.ignored.store(true, Ordering::Relaxed);
+        } else if let Self::Mock(mock) = self {
+            mock.ignored.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Record an error in the transaction.
+    ///
+    /// This can be used to track errors which don't affect the response
+    /// status, such as a failed call to a downstream service.
+    ///
+    /// `code` is an arbitrary integer indicating the error priority,
+    /// `message` is the error message and `class` is the error class or type.
+    pub fn notice_error(&self, code: i32, message: &str, class: &str) {
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                if let Err(e) = t.notice_error(code, message, class) {
+                    warn!("Could not add error to New Relic transaction: {}", e);
+                }
+            }
+        } else if let Self::Mock(mock) = self {
+            mock.notice_error(code, message, class);
+        }
+    }
+
+    /// Record the error which is being responded with, so that the fairing
+    /// doesn't also record an error for the response's status.
+    fn notice_response_error(&self, code: i32, message: &str, class: &str) {
+        self.notice_error(code, message, class);
+        if let Self::Running(inner) = self {
+            inner.response_error_noticed.store(true, Ordering::Relaxed);
+        } else if let Self::Mock(mock) = self {
+            mock.response_error_noticed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a custom metric for the transaction's app.
+    ///
+    /// The SDK records custom metric values as milliseconds, so `value` is
+    /// rounded down to a whole number; negative values can't be recorded.
+    /// New Relic expects custom metric names to begin with `Custom/`.
+    ///
+    /// ```rust
+    /// use rocket::local::blocking::Client;
+    /// use rocket_newrelic::{NewRelic, Transaction};
+    ///
+    /// #[rocket::post("/cart")]
+    /// fn update_cart(transaction: &Transaction) -> &'static str {
+    ///     transaction.record_metric("Custom/cart/items", 3.0);
+    ///     "Updated"
+    /// }
+    ///
+    /// let (newrelic, recorder) = NewRelic::mock();
+    /// let rocket = rocket::build()
+    ///     .attach(newrelic)
+    ///     .mount("/", rocket::routes![update_cart]);
+    /// let client = Client::tracked(rocket).expect("valid rocket");
+    /// client.post("/cart").dispatch();
+    ///
+    /// let transactions = recorder.transactions();
+    /// assert_eq!(
+    ///     transactions[0].metrics,
+    ///     vec![("Custom/cart/items".to_string(), 3.0)]
+    /// );
+    /// ```
+    pub fn record_metric(&self, name: &str, value: f64) {
+        let duration = match Duration::try_from_secs_f64(value / 1000.0) {
+            Ok(duration) => duration,
+            Err(e) => {
+                warn!("Could not record custom metric {}: {}", name, e);
+                return;
+            }
+        };
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                if let Err(e) = t.record_custom_metric(name, duration) {
+                    warn!("Could not record custom metric {}: {}", name, e);
+                }
+            }
+        } else if let Self::Mock(mock) = self {
+            mock.record_metric(name, value);
+        }
+    }
+
+    /// Record a custom event with the given type and attributes.
+    ///
+    /// Custom events are reported alongside the transaction and can be used
+    /// to track discrete business events, such as an order being placed.
+    /// Redacted attribute keys are handled in the same way as
+    /// `add_attribute`.
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// use newrelic::Attribute;
+    ///
+    /// #[rocket::post("/order")]
+    /// fn place_order(transaction: &Transaction) -> &'static str {
+    ///     transaction.record_custom_event(
+    ///         "OrderPlaced",
+    ///         &[("items", Attribute::Int(3)), ("currency", Attribute::String("GBP"))],
+    ///     );
+    ///     "Order placed"
+    /// }
+    /// ```
+    pub fn record_custom_event(
+        &self,
+        event_type: &str,
+        attributes: &[(&str, newrelic::Attribute)],
+    ) {
+        if let Self::Running(inner) = self {
+            if let Some(t) = inner.try_read() {
+                match t.custom_event(event_type) {
+                    Ok(event) => {
+                        for (key, attribute) in attributes {
+                            let attribute = inner.config.redact(key, copy_attribute(attribute));
+                            if let Err(e) = event.add_attribute(key, attribute) {
+                                warn!("Could not add {} attribute to custom event: {}", key, e);
+                            }
+                        }
+                        event.record();
+                    }
+                    Err(e) => {
+                        warn!("Could not create custom event: {}", e);
+                    }
+                }
+            }
+        } else if let Self::Mock(mock) = self {
+            mock.record_custom_event(event_type, attributes);
+        }
+    }
+
+    /// Track a segment being opened, or return `None` if the transaction
+    /// already has the maximum number of segments open.
+    fn enter_segment(&self) -> Option<OpenSegment<'_>> {
+        let (open_segments, config) = match self {
+            Self::Running(inner) => (&inner.open_segments, &inner.config),
+            Self::Mock(mock) => (&mock.open_segments, &mock.config),
+            Self::None => return Some(OpenSegment(None)),
+        };
+        let open = open_segments.fetch_add(1, Ordering::Relaxed);
+        let guard = OpenSegment(Some(open_segments));
+        match config.max_open_segments {
+            Some(max) if open >= max => {
+                debug!("Maximum number of open segments reached, not creating segment");
+                None
+            }
+            _ => Some(guard),
+        }
+    }
+
+    /// Execute the function in a named custom segment.
+    ///
+    /// `func` should be a function taking a `newrelic::Segment`. This allows
+    /// nested segments to be created using methods of the passed segment.
+    ///
+    /// If the current transaction could not be registered, this just calls the
+    /// given function outside of a segment.
+    pub fn custom_segment<F, V>(&self, name: &str, category: &str, func: F) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        self.with_locked(|t| t.custom_segment(name, category, func))
+    }
+
+    /// Execute the function with the transaction locked, so that several
+    /// segments can be created without acquiring the lock for each one.
+    ///
+    /// `func` is passed a [`LockedTransaction`], which has the same segment
+    /// methods as `Transaction`:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// use newrelic::Datastore;
+    ///
+    /// #[rocket::get("/dashboard")]
+    /// fn dashboard(transaction: &Transaction) -> String {
+    ///     transaction.with_locked(|t| {
+    ///         let users = t.datastore_segment(Datastore::Postgres, "users", "select", "", |_| 3);
+    ///         let orders = t.datastore_segment(Datastore::Postgres, "orders", "select", "", |_| 5);
+    ///         t.custom_segment("render", "templates", |_| format!("{} {}", users, orders))
+    ///     })
+    /// }
+    /// ```
+    ///
+    /// The transaction can't be ended while `func` is running.
+    pub fn with_locked<F, V>(&self, func: F) -> V
+    where
+        F: FnOnce(&LockedTransaction<'_>) -> V,
+    {
+        let guard = match self {
+            Self::Running(inner) => inner.try_read(),
+            _ => None,
+        };
+        func(&LockedTransaction {
+            transaction: self,
+            guard,
+        })
+    }
+
+    /// Execute the function in a custom segment named after the transaction,
+    /// e.g. to time a whole handler body.
+    ///
+    /// The segment is named after the route which began the transaction,
+    /// even if it has since been renamed. See `custom_segment` for more
+    /// details.
+    pub fn timed<F, V>(&self, category: &str, func: F) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let name = match self {
+            Self::Running(inner) => inner.name.as_str(),
+            Self::Mock(mock) => mock.name.as_str(),
+            Self::None => "",
+        };
+        let name = if name.is_empty() {
+            "unknown_handler"
+        } else {
+            name
+        };
+        self.custom_segment(name, category, func)
+    }
+
+    /// Start a named custom segment, which lasts until the returned handle is
+    /// dropped (or `SegmentHandle::end` is called).
+    ///
+    /// Unlike `custom_segment`, this allows a segment to span arbitrary
+    /// control flow rather than a single closure:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// #[rocket::get("/report?<full>")]
+    /// fn report(transaction: &Transaction, full: bool) -> &'static str {
+    ///     let segment = transaction.start_custom_segment("generate report", "reports");
+    ///     if !full {
+    ///         return "summary";
+    ///     }
+    ///     let report = "full report";
+    ///     segment.end();
+    ///     report
+    /// }
+    /// ```
+    ///
+    /// If the handle is still alive when the response is sent, the
+    /// transaction is only ended once the handle is dropped, so it shouldn't
+    /// be held beyond the handler.
+    ///
+    /// If the current transaction could not be registered, the handle does
+    /// nothing.
+    pub fn start_custom_segment(&self, name: &str, category: &str) -> SegmentHandle<'_> {
+        let open = match self.enter_segment() {
+            Some(open) => open,
+            None => return SegmentHandle::none(),
+        };
+        match self {
+            Transaction::Running(inner) => match inner.try_read_owned() {
+                Some(guard) => {
+                    let transaction: *const newrelic::Transaction = &*guard;
+                    // SAFETY: the guard owns a reference to the lock, so the
+                    // transaction it points to is kept alive by the guard
+                    // alone, and it can't be ended (or freed) while the read
+                    // lock is held. The handle holds the guard, and ends the
+                    // segment when it's dropped before releasing the guard,
+                    // so the reference is valid for the segment's lifetime.
+                    let segment = unsafe { &*transaction }.create_custom_segment(name, category);
+                    SegmentHandle {
+                        segment,
+                        _guard: Some(guard),
+                        _open: Some(open),
+                    }
+                }
+                None => SegmentHandle::none(),
+            },
+            Transaction::Mock(mock) => {
+                mock.custom_segment(name, category);
+                SegmentHandle {
+                    segment: newrelic::Segment::default(),
+                    _guard: None,
+                    _open: Some(open),
+                }
+            }
+            Transaction::None => SegmentHandle::none(),
+        }
+    }
+
+    /// Execute the function in a datastore segment.
+    ///
+    /// `func` should be a function taking a `newrelic::Segment`. This allows
+    /// nested segments to be created using methods of the passed segment.
+    ///
+    /// `operation` can be an [`Operation`] or a string, e.g. `"select"`. The
+    /// `table` and `operation` arguments should not contain any slash
+    /// characters, unless [`NewRelicBuilder::sanitize_segment_names`] is
+    /// enabled.
+    ///
+    /// If the current transaction could not be registered, this just calls the
+    /// given function with a `newrelic::Segment::None`.
+    ///
+    /// See `newrelic::DatastoreParamsBuilder` and
+    /// `newrelic::Transaction::datastore_segment` for more details.
+    ///
+    /// SQL Obfuscation
+    /// ---------------
+    ///
+    /// The supplied SQL string will go through the New Relic SDK's
+    /// basic literal replacement obfuscator that strips the SQL
+    /// string literals (values between single or double quotes) and
+    /// numeric sequences, replacing them with the ? character.
+    /// For example:
+    ///
+    /// This SQL:
+    ///      SELECT * FROM table WHERE ssn=‘000-00-0000’
+    ///
+    /// obfuscates to:
+    ///      SELECT * FROM table WHERE ssn=?
+    ///
+    /// Because the default obfuscator just replaces literals, there
+    /// could be cases that it does not handle well. For instance, it
+    /// will not strip out comments from your SQL string, it will not
+    /// handle certain database-specific language features, and it
     /// could fail for other complex cases.
-    pub fn datastore_segment<F, V>(
+    pub fn datastore_segment<O, F, V>(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: O,
+        sql: &str,
+        func: F,
+    ) -> V
+    where
+        O: Into<Operation>,
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        self.with_locked(|t| t.datastore_segment(datastore, table, operation, sql, func))
+    }
+
+    /// Execute the function in a datastore segment, using parameters which
+    /// have already been built.
+    ///
+    /// Unlike `datastore_segment`, this allows any of the fields of
+    /// `newrelic::DatastoreParamsBuilder` to be set, such as the host, port
+    /// and database name:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// use newrelic::{Datastore, DatastoreParamsBuilder};
+    ///
+    /// #[rocket::get("/users")]
+    /// fn get_users(transaction: &Transaction) -> &'static str {
+    ///     let params = DatastoreParamsBuilder::new(Datastore::Postgres)
+    ///         .collection("users")
+    ///         .operation("select")
+    ///         .host("db.example.com")
+    ///         .database_name("app")
+    ///         .build()
+    ///         .expect("Invalid datastore parameters");
+    ///     transaction.datastore_segment_with(&params, |_| "users")
+    /// }
+    /// ```
+    ///
+    /// If the current transaction could not be registered, this just calls the
+    /// given function with a `newrelic::Segment::None`.
+    pub fn datastore_segment_with<F, V>(&self, params: &newrelic::DatastoreParams, func: F) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        self.with_locked(|t| t.datastore_segment_with(params, func))
+    }
+
+    /// Execute a function using a pooled database connection in a datastore
+    /// segment.
+    ///
+    /// This is a convenience for when the query's SQL isn't readily
+    /// available, e.g. when using connections from an `r2d2` pool: `conn` is
+    /// passed straight to `func`, and no SQL is recorded in the segment.
+    /// See `datastore_segment` for details of the other arguments.
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// # struct Connection;
+    /// # impl Connection {
+    /// #     fn count_users(&self) -> usize { 0 }
+    /// # }
+    /// # fn count(transaction: &Transaction, conn: Connection) {
+    /// use newrelic::Datastore;
+    ///
+    /// let users = transaction.pooled_segment(Datastore::Postgres, "users", "select", conn, |conn| {
+    ///     conn.count_users()
+    /// });
+    /// # }
+    /// ```
+    pub fn pooled_segment<O, C, F, V>(
         &self,
         datastore: newrelic::Datastore,
         table: &str,
-        operation: &str,
-        sql: &str,
+        operation: O,
+        conn: C,
         func: F,
     ) -> V
     where
-        F: FnOnce(newrelic::Segment) -> V,
+        O: Into<Operation>,
+        F: FnOnce(C) -> V,
     {
-        match self {
-            Transaction::Running(inner) => match inner.0.read() {
-                Ok(t) => {
-                    let params = newrelic::DatastoreParamsBuilder::new(datastore)
-                        .collection(table)
-                        .operation(operation)
-                        .query(sql)
-                        .build();
-                    match params {
-                        Ok(p) => t.datastore_segment(&p, func),
-                        Err(e) => {
-                            warn!("Error building datastore parameters: {}", e);
-                            func(newrelic::Segment::default())
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("Error locking transaction RwLock: {}", e);
-                    func(newrelic::Segment::default())
-                }
-            },
-            Transaction::None => func(newrelic::Segment::default()),
-        }
+        self.datastore_segment(datastore, table, operation, "", |_| func(conn))
     }
 
     /// Execute a function in an external segment.
@@ -502,7 +3955,8 @@ impl Transaction {
     /// nested segments to be created using methods of the passed segment.
     ///
     /// The `procedure` and `library` arguments, if provided, should not
-    /// contain any slash characters.
+    /// contain any slash characters, unless
+    /// [`NewRelicBuilder::sanitize_segment_names`] is enabled.
     ///
     /// If the current transaction could not be registered, this just calls the
     /// given function with a `newrelic::Segment::None`.
@@ -519,30 +3973,339 @@ impl Transaction {
     where
         F: FnOnce(newrelic::Segment) -> V,
     {
+        self.with_locked(|t| t.external_segment(host, procedure, library, func))
+    }
+
+    /// Execute an async function in a named custom segment.
+    ///
+    /// Unlike `custom_segment`, the segment is kept open until the future
+    /// returned by `func` has completed, so the segment's duration includes
+    /// any awaited work. Nested segments can't be created from the future.
+    ///
+    /// If the current transaction could not be registered, this just awaits
+    /// the future outside of a segment.
+    pub async fn custom_segment_async<F, Fut, V>(&self, name: &str, category: &str, func: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let _open = match self.enter_segment() {
+            Some(open) => open,
+            None => return func().await,
+        };
         match self {
-            Transaction::Running(inner) => match inner.0.read() {
-                Ok(t) => {
-                    let mut params = newrelic::ExternalParamsBuilder::new(host);
-                    if let Some(p) = procedure {
-                        params = params.procedure(p);
-                    }
-                    if let Some(l) = library {
-                        params = params.library(l);
+            Transaction::Running(inner) => {
+                let t = match inner.read().await {
+                    Some(t) => t,
+                    None => return func().await,
+                };
+                let _segment = t.create_custom_segment(name, category);
+                func().await
+            }
+            Transaction::Mock(mock) => {
+                mock.custom_segment(name, category);
+                func().await
+            }
+            Transaction::None => func().await,
+        }
+    }
+
+    /// Wrap a future in a named custom segment, which is kept open until the
+    /// future completes.
+    ///
+    /// This is `custom_segment_async` for a future which has already been
+    /// created, e.g. by a service stored in managed state:
+    ///
+    /// ```rust
+    /// use rocket::State;
+    /// use rocket_newrelic::Transaction;
+    ///
+    /// struct Inventory;
+    ///
+    /// impl Inventory {
+    ///     async fn count(&self) -> usize {
+    ///         42
+    ///     }
+    /// }
+    ///
+    /// #[rocket::get("/stock")]
+    /// async fn stock(inventory: &State<Inventory>, transaction: &Transaction) -> String {
+    ///     let count = transaction
+    ///         .instrument("count", "inventory", inventory.count())
+    ///         .await;
+    ///     count.to_string()
+    /// }
+    /// ```
+    pub async fn instrument<Fut>(&self, name: &str, category: &str, fut: Fut) -> Fut::Output
+    where
+        Fut: Future,
+    {
+        self.custom_segment_async(name, category, || fut).await
+    }
+
+    /// Execute an async function in a datastore segment.
+    ///
+    /// The segment is kept open until the future returned by `func` has
+    /// completed. See `datastore_segment` for details of the other arguments.
+    ///
+    /// If the current transaction could not be registered, this just awaits
+    /// the future outside of a segment.
+    pub async fn datastore_segment_async<O, F, Fut, V>(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: O,
+        sql: &str,
+        func: F,
+    ) -> V
+    where
+        O: Into<Operation>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let _open = match self.enter_segment() {
+            Some(open) => open,
+            None => return func().await,
+        };
+        let operation = operation.into();
+        let operation = operation.as_str();
+        match self {
+            Transaction::Running(inner) => {
+                let t = match inner.read().await {
+                    Some(t) => t,
+                    None => return func().await,
+                };
+                let params = inner
+                    .config
+                    .datastore_params(datastore, table, operation, sql);
+                match params {
+                    Ok(p) => {
+                        let _segment = t.create_datastore_segment(&p);
+                        func().await
                     }
-                    match params.build() {
-                        Ok(p) => t.external_segment(&p, func),
-                        Err(e) => {
-                            warn!("Error building external New Relic parameters: {}", e);
-                            func(newrelic::Segment::default())
-                        }
+                    Err(e) => {
+                        warn!("Error building datastore parameters: {}", e);
+                        func().await
                     }
                 }
-                Err(e) => {
-                    warn!("Error locking transaction RwLock: {}", e);
-                    func(newrelic::Segment::default())
+            }
+            Transaction::Mock(mock) => {
+                mock.datastore_segment(&datastore, table, operation, sql);
+                func().await
+            }
+            Transaction::None => func().await,
+        }
+    }
+
+    /// Execute an async function using a pooled database connection in a
+    /// datastore segment, e.g. `|conn| conn.run(|c| ...)` with
+    /// `rocket_sync_db_pools`.
+    ///
+    /// The segment is kept open until the future returned by `func` has
+    /// completed. See `pooled_segment` for more details.
+    pub async fn pooled_segment_async<O, C, F, Fut, V>(
+        &self,
+        datastore: newrelic::Datastore,
+        table: &str,
+        operation: O,
+        conn: C,
+        func: F,
+    ) -> V
+    where
+        O: Into<Operation>,
+        F: FnOnce(C) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        self.datastore_segment_async(datastore, table, operation, "", || func(conn))
+            .await
+    }
+
+    /// Execute an async function in an external segment.
+    ///
+    /// The segment is kept open until the future returned by `func` has
+    /// completed. See `external_segment` for details of the other arguments.
+    ///
+    /// If the current transaction could not be registered, this just awaits
+    /// the future outside of a segment.
+    pub async fn external_segment_async<F, Fut, V>(
+        &self,
+        host: &str,
+        procedure: Option<&str>,
+        library: Option<&str>,
+        func: F,
+    ) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let _open = match self.enter_segment() {
+            Some(open) => open,
+            None => return func().await,
+        };
+        match self {
+            Transaction::Running(inner) => {
+                let t = match inner.read().await {
+                    Some(t) => t,
+                    None => return func().await,
+                };
+                let procedure = procedure.map(|p| inner.config.segment_name(p));
+                let library = library.map(|l| inner.config.segment_name(l));
+                let mut params = newrelic::ExternalParamsBuilder::new(host);
+                if let Some(p) = &procedure {
+                    params = params.procedure(p);
                 }
-            },
-            Transaction::None => func(newrelic::Segment::default()),
+                if let Some(l) = &library {
+                    params = params.library(l);
+                }
+                match params.build() {
+                    Ok(p) => {
+                        let _segment = t.create_external_segment(&p);
+                        func().await
+                    }
+                    Err(e) => {
+                        warn!("Error building external New Relic parameters: {}", e);
+                        func().await
+                    }
+                }
+            }
+            Transaction::Mock(mock) => {
+                mock.external_segment(host, procedure, library);
+                func().await
+            }
+            Transaction::None => func().await,
+        }
+    }
+
+    /// Execute a function in an external segment, flagging slow calls.
+    ///
+    /// This behaves like `external_segment`, but also measures how long
+    /// `func` takes. If it takes longer than `threshold`, the duration in
+    /// milliseconds is added to the transaction as the
+    /// `external.duration_ms` attribute, making slow dependencies easy to
+    /// find. If several calls exceed the threshold, the attribute holds the
+    /// duration of the latest.
+    ///
+    /// The SDK doesn't enforce the threshold; `func` is never cancelled.
+    pub fn external_segment_timed<F, V>(
+        &self,
+        host: &str,
+        procedure: Option<&str>,
+        library: Option<&str>,
+        threshold: Duration,
+        func: F,
+    ) -> V
+    where
+        F: FnOnce(newrelic::Segment) -> V,
+    {
+        let start = Instant::now();
+        let value = self.external_segment(host, procedure, library, func);
+        self.flag_slow_external(start.elapsed(), threshold);
+        value
+    }
+
+    /// Execute an async function in an external segment, flagging slow
+    /// calls.
+    ///
+    /// See `external_segment_timed` and `external_segment_async` for more
+    /// details.
+    pub async fn external_segment_timed_async<F, Fut, V>(
+        &self,
+        host: &str,
+        procedure: Option<&str>,
+        library: Option<&str>,
+        threshold: Duration,
+        func: F,
+    ) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let start = Instant::now();
+        let value = self
+            .external_segment_async(host, procedure, library, func)
+            .await;
+        self.flag_slow_external(start.elapsed(), threshold);
+        value
+    }
+
+    /// Record the duration of an external call if it exceeded `threshold`.
+    fn flag_slow_external(&self, elapsed: Duration, threshold: Duration) {
+        if elapsed > threshold {
+            self.add_attribute("external.duration_ms", duration_ms(elapsed));
+        }
+    }
+}
+
+/// Managed state marking that the fairing has been attached.
+struct FairingAttached;
+
+/// Log that a transaction guard was used without the fairing attached, so the
+/// request isn't instrumented.
+///
+/// This is only a debug log, since routes using the guards are already
+/// checked at launch by `check_fairing_attached`.
+fn log_fairing_missing() {
+    debug!("The NewRelic fairing isn't attached, so the request won't be instrumented");
+}
+
+/// Check at launch that the fairing is attached to `rocket`, since one of its
+/// routes uses the transaction guard `guard`, logging an error if not.
+///
+/// Rocket runs this once for each guard type used by each app, so every
+/// misconfigured app is reported, even if another app in the same process is
+/// set up correctly. This never aborts launch, since uninstrumented requests
+/// are still handled as normal.
+fn check_fairing_attached(rocket: &Rocket<Ignite>, guard: &str) -> bool {
+    if rocket.state::<FairingAttached>().is_none() {
+        error!(
+            "The `{}` request guard is used but the NewRelic fairing isn't \
+             attached, so requests won't be instrumented; attach it using \
+             `rocket.attach(newrelic)` rather than managing it as state",
+            guard
+        );
+    }
+    false
+}
+
+impl Sentinel for Transaction {
+    fn abort(rocket: &Rocket<Ignite>) -> bool {
+        check_fairing_attached(rocket, "&Transaction")
+    }
+}
+
+impl Sentinel for BackgroundTransaction {
+    fn abort(rocket: &Rocket<Ignite>) -> bool {
+        check_fairing_attached(rocket, "&BackgroundTransaction")
+    }
+}
+
+impl Sentinel for OwnedTransaction {
+    fn abort(rocket: &Rocket<Ignite>) -> bool {
+        check_fairing_attached(rocket, "OwnedTransaction")
+    }
+}
+
+impl Sentinel for AppRef<'_> {
+    fn abort(rocket: &Rocket<Ignite>) -> bool {
+        check_fairing_attached(rocket, "AppRef")
+    }
+}
+
+/// Get the request's web transaction, beginning it if necessary.
+///
+/// This implies that ONLY requests which include a Transaction in their
+/// request guards will be traced (unless `instrument_all` is enabled).
+/// Note that this will only produce a valid transaction if the NewRelic
+/// fairing has been attached.
+fn request_transaction<'r>(request: &'r Request<'_>) -> &'r Arc<Transaction> {
+    match request.local_cache(|| AppWrapper::None) {
+        AppWrapper::App(ref app, ref config) => request.local_cache(|| {
+            let kind = config.transaction_kind(request);
+            Arc::new(Transaction::new(app, config, request, kind))
+        }),
+        AppWrapper::None => {
+            log_fairing_missing();
+            request.local_cache(|| Arc::new(Transaction::None))
         }
     }
 }
@@ -554,19 +4317,254 @@ where
 {
     type Error = ();
 
-    // Begin the New Relic transaction here. This implies that ONLY requests
-    // which include a Transaction in their request guards will be traced.
-    // Note that this will only produce a valid transaction if the NewRelic
-    // fairing has been attached.
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        HandlerStarted::record(request);
+        Outcome::Success(&**request_transaction(request))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OwnedTransaction {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        HandlerStarted::record(request);
+        Outcome::Success(OwnedTransaction(Arc::clone(request_transaction(request))))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AppRef<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.local_cache(|| AppWrapper::None) {
+            AppWrapper::App(AppHandle::Sdk(app), _) => Outcome::Success(AppRef(Some(app))),
+            AppWrapper::App(AppHandle::Mock(_), _) => Outcome::Success(AppRef(None)),
+            AppWrapper::None => {
+                log_fairing_missing();
+                Outcome::Success(AppRef(None))
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'r> for &'a BackgroundTransaction
+where
+    'r: 'a,
+{
+    type Error = ();
+
+    // As with `Transaction`, the non-web transaction is only begun for
+    // requests which include it in their request guards.
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        HandlerStarted::record(request);
         let transaction = match request.local_cache(|| AppWrapper::None) {
-            AppWrapper::App(ref app) => request.local_cache(|| Transaction::new(app, request)),
-            AppWrapper::None => request.local_cache(|| Transaction::None),
+            AppWrapper::App(ref app, ref config) => request.local_cache(|| {
+                BackgroundTransaction(Transaction::new(
+                    app,
+                    config,
+                    request,
+                    TransactionKind::NonWeb,
+                ))
+            }),
+            AppWrapper::None => {
+                log_fairing_missing();
+                request.local_cache(|| BackgroundTransaction(Transaction::None))
+            }
         };
         Outcome::Success(transaction)
     }
 }
 
+/// A request guard wrapping one of the transaction guards which forwards,
+/// rather than succeeding with an uninstrumented transaction, if the
+/// fairing isn't attached.
+///
+/// This surfaces setup mistakes through Rocket's routing, e.g. to a
+/// lower-ranked fallback route. `T` can be `&Transaction`,
+/// `&BackgroundTransaction`, `OwnedTransaction` or `AppRef`, which are
+/// available through `Deref`.
+///
+/// ```rust
+/// use rocket::local::blocking::Client;
+/// use rocket_newrelic::{Strict, Transaction};
+///
+/// #[rocket::get("/")]
+/// fn index(_transaction: Strict<&Transaction>) -> &'static str {
+///     "Instrumented"
+/// }
+///
+/// #[rocket::get("/", rank = 2)]
+/// fn fallback() -> &'static str {
+///     "Not instrumented"
+/// }
+///
+/// // The fairing isn't attached, so the fallback handles the request.
+/// let rocket = rocket::build().mount("/", rocket::routes![index, fallback]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// assert_eq!(client.get("/").dispatch().into_string().unwrap(), "Not instrumented");
+/// ```
+#[derive(Clone)]
+pub struct Strict<T>(T);
+
+// `Strict` is how routes opt out of uninstrumented requests, so routes using
+// it aren't reported at launch by the sentinel of the guard it wraps.
+impl<T> Sentinel for Strict<T> {
+    fn abort(_: &Rocket<Ignite>) -> bool {
+        false
+    }
+}
+
+impl<T> Deref for Strict<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromRequest<'r>> FromRequest<'r> for Strict<T> {
+    type Error = T::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.local_cache(|| AppWrapper::None) {
+            AppWrapper::App(..) => T::from_request(request).await.map(Strict),
+            AppWrapper::None => Outcome::Forward(Status::InternalServerError),
+        }
+    }
+}
+
+/// A `Result` responder which records errors in the request's transaction.
+///
+/// If the result is an `Err`, it's recorded in the transaction using
+/// [`Transaction::notice_error`], with the error's `Display` output as the
+/// message and its type as the class, before the error is used as the
+/// response. The fairing then doesn't record a second error for the
+/// response's status. Both variants are otherwise responded to as normal,
+/// as they would be by `Result`'s own `Responder` implementation.
+///
+/// ```rust
+/// use rocket::{http::Status, local::blocking::Client};
+/// use rocket_newrelic::{NewRelic, TracedResult, Transaction};
+///
+/// #[derive(Debug, rocket::Responder)]
+/// #[response(status = 503)]
+/// struct Unavailable(&'static str);
+///
+/// impl std::fmt::Display for Unavailable {
+///     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+///         f.write_str(self.0)
+///     }
+/// }
+///
+/// #[rocket::get("/stock")]
+/// fn stock(_transaction: &Transaction) -> TracedResult<&'static str, Unavailable> {
+///     Err(Unavailable("stock service is down")).into()
+/// }
+///
+/// let (newrelic, recorder) = NewRelic::mock();
+/// let rocket = rocket::build()
+///     .attach(newrelic)
+///     .mount("/", rocket::routes![stock]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// assert_eq!(client.get("/stock").dispatch().status(), Status::ServiceUnavailable);
+///
+/// let errors = &recorder.transactions()[0].errors;
+/// assert_eq!(errors.len(), 1);
+/// assert_eq!(errors[0].message, "stock service is down");
+/// ```
+#[derive(Debug)]
+pub struct TracedResult<T, E>(pub Result<T, E>);
+
+impl<T, E> From<Result<T, E>> for TracedResult<T, E> {
+    fn from(result: Result<T, E>) -> Self {
+        Self(result)
+    }
+}
+
+impl<'r, 'o: 'r, T, E> Responder<'r, 'o> for TracedResult<T, E>
+where
+    T: Responder<'r, 'o>,
+    E: Responder<'r, 'o> + Display,
+{
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        match self.0 {
+            Ok(value) => value.respond_to(request),
+            Err(e) => {
+                let message = e.to_string();
+                let response = e.respond_to(request);
+                let status = match &response {
+                    Ok(response) => response.status(),
+                    Err(status) => *status,
+                };
+                request
+                    .local_cache(|| Arc::new(Transaction::None))
+                    .notice_response_error(
+                        i32::from(status.code),
+                        &message,
+                        std::any::type_name::<E>(),
+                    );
+                response
+            }
+        }
+    }
+}
+
+/// A data guard which times reading the request body into `T`.
+///
+/// The body is read in a `Read body` custom segment of the request's
+/// transaction, and the time taken is added as a `body.read_ms` attribute,
+/// so that upload time can be told apart from the rest of the handler.
+/// Rocket resolves the data guard after the request guards, so the route
+/// needs a [`Transaction`] guard (or `instrument_all`) for the body to be
+/// timed.
+///
+/// The fairing can't time body reads by itself, since fairings can only
+/// peek at the start of the body rather than wrap it, so this guard needs
+/// to be used by each route whose uploads should be timed.
+///
+/// ```rust
+/// use rocket::local::blocking::Client;
+/// use rocket_newrelic::{NewRelic, TimedData, Transaction};
+///
+/// #[rocket::post("/upload", data = "<body>")]
+/// fn upload(_transaction: &Transaction, body: TimedData<String>) -> String {
+///     format!("Uploaded {} bytes", body.0.len())
+/// }
+///
+/// let (newrelic, recorder) = NewRelic::mock();
+/// let rocket = rocket::build()
+///     .attach(newrelic)
+///     .mount("/", rocket::routes![upload]);
+/// let client = Client::tracked(rocket).expect("valid rocket");
+/// client.post("/upload").body("hello").dispatch();
+///
+/// assert!(recorder.transactions()[0].attribute("body.read_ms").is_some());
+/// ```
+#[derive(Debug)]
+pub struct TimedData<T>(pub T);
+
+#[rocket::async_trait]
+impl<'r, T: FromData<'r>> FromData<'r> for TimedData<T> {
+    type Error = T::Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let transaction = request.local_cache(|| Arc::new(Transaction::None));
+        let start = Instant::now();
+        let outcome = transaction
+            .custom_segment_async("Read body", "Rocket", || T::from_data(request, data))
+            .await;
+        transaction.add_attribute("body.read_ms", duration_ms(start.elapsed()));
+        outcome.map(TimedData)
+    }
+}
+
+#[cfg(feature = "diesel")]
+pub use self::diesel::DieselDatastore;
+
 #[cfg(feature = "diesel")]
 mod diesel {
     use diesel::{
@@ -577,13 +4575,80 @@ mod diesel {
         query_dsl::{methods::LimitDsl, LoadQuery},
     };
     use log::warn;
+    use rocket::tokio::task::spawn_blocking;
 
-    use super::Transaction;
+    use super::{Operation, Transaction};
+
+    impl Transaction {
+        /// Execute a Diesel query in a datastore segment, using `run` to
+        /// execute it and return the results.
+        ///
+        /// This allows any way of executing the query to be recorded, for
+        /// example passing `users.limit(10)` along with
+        /// `|query, conn| query.load(conn)` loads the first ten rows.
+        ///
+        /// The query's SQL (including any `LIMIT`) is recorded in the
+        /// segment, and the operation is taken from its first keyword, e.g.
+        /// `select` or `update`.
+        ///
+        /// See `Transaction::datastore_segment` for more details.
+        ///
+        /// *Note*: requires the `diesel` feature.
+        pub fn diesel_segment<T, Conn, B, R, F>(
+            &self,
+            datastore: newrelic::Datastore,
+            table: &str,
+            query: T,
+            conn: &Conn,
+            run: F,
+        ) -> QueryResult<R>
+        where
+            T: QueryFragment<B>,
+            B: Backend,
+            B::QueryBuilder: Default,
+            F: FnOnce(T, &Conn) -> QueryResult<R>,
+        {
+            let _open = match self.enter_segment() {
+                Some(open) => open,
+                None => return run(query, conn),
+            };
+            match self {
+                Transaction::Running(inner) => match inner.try_read() {
+                    Some(t) => {
+                        let sql = debug_query(&query).to_string();
+                        let params = inner.config.datastore_params(
+                            datastore,
+                            table,
+                            Operation::from_sql(&sql).as_str(),
+                            &sql,
+                        );
+                        match params {
+                            Ok(p) => t.datastore_segment(&p, |_| run(query, conn)),
+                            Err(e) => {
+                                warn!("Error building New Relic datastore parameters: {}", e);
+                                run(query, conn)
+                            }
+                        }
+                    }
+                    None => run(query, conn),
+                },
+                Transaction::Mock(mock) => {
+                    let sql = debug_query(&query).to_string();
+                    mock.datastore_segment(
+                        &datastore,
+                        table,
+                        Operation::from_sql(&sql).as_str(),
+                        &sql,
+                    );
+                    run(query, conn)
+                }
+                Transaction::None => run(query, conn),
+            }
+        }
 
-    impl Transaction {
         /// Execute a Diesel query in a datastore segment,  returning the first row.
         ///
-        /// See `Transaction::datastore_segment` for more details.
+        /// See `Transaction::diesel_segment` for more details.
         ///
         /// *Note*: requires the `diesel` feature.
         pub fn diesel_segment_first<T, Conn, B, V>(
@@ -599,35 +4664,14 @@ mod diesel {
             B::QueryBuilder: Default,
             Limit<T>: LoadQuery<Conn, V>,
         {
-            match self {
-                Transaction::Running(inner) => match inner.0.read() {
-                    Ok(t) => {
-                        let sql = debug_query(&query).to_string();
-                        let params = newrelic::DatastoreParamsBuilder::new(datastore)
-                            .collection(table)
-                            .operation("select")
-                            .query(&sql)
-                            .build();
-                        match params {
-                            Ok(p) => t.datastore_segment(&p, |_| query.first(conn)),
-                            Err(e) => {
-                                warn!("Error building New Relic datastore parameters: {}", e);
-                                query.first(conn)
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Error locking transaction RwLock: {}", e);
-                        query.first(conn)
-                    }
-                },
-                Transaction::None => query.first(conn),
-            }
+            self.diesel_segment::<T, Conn, B, V, _>(datastore, table, query, conn, |query, conn| {
+                query.first(conn)
+            })
         }
 
         /// Execute a Diesel query in a datastore segment, loading the results.
         ///
-        /// See `Transaction::datastore_segment` for more details.
+        /// See `Transaction::diesel_segment` for more details.
         ///
         /// *Note*: requires the `diesel` feature.
         pub fn diesel_segment_load<T, Conn, B, V>(
@@ -642,31 +4686,739 @@ mod diesel {
             B: Backend,
             B::QueryBuilder: Default,
         {
+            self.diesel_segment::<T, Conn, B, Vec<V>, _>(
+                datastore,
+                table,
+                query,
+                conn,
+                |query, conn| query.load(conn),
+            )
+        }
+
+        /// Execute a Diesel query on a blocking thread in a datastore segment,
+        /// loading the results.
+        ///
+        /// The query and connection are moved to Tokio's blocking thread pool
+        /// using `spawn_blocking`, so that synchronous Diesel can be used from
+        /// async handlers without blocking the executor. The segment is kept
+        /// open until the query has completed. The connection is returned
+        /// along with the results, so that it can be used again:
+        ///
+        /// ```rust,no_run
+        /// # use diesel::{dsl::sql, pg::Pg, sql_types::Text, PgConnection, QueryResult};
+        /// # use rocket_newrelic::Transaction;
+        /// # async fn names(transaction: &Transaction, conn: PgConnection) -> QueryResult<()> {
+        /// use newrelic::Datastore;
+        ///
+        /// let query = diesel::select(sql::<Text>("name FROM users"));
+        /// let (conn, users) = transaction
+        ///     .diesel_segment_load_blocking::<_, _, Pg, String>(Datastore::Postgres, "users", query, conn)
+        ///     .await;
+        /// let query = diesel::select(sql::<Text>("name FROM admins"));
+        /// let (_conn, admins) = transaction
+        ///     .diesel_segment_load_blocking::<_, _, Pg, String>(Datastore::Postgres, "admins", query, conn)
+        ///     .await;
+        /// # let (_users, _admins) = (users?, admins?);
+        /// # Ok(())
+        /// # }
+        /// ```
+        ///
+        /// See `Transaction::datastore_segment` for more details.
+        ///
+        /// *Note*: requires the `diesel` feature.
+        ///
+        /// # Panics
+        ///
+        /// Panics if executing the query panics.
+        pub async fn diesel_segment_load_blocking<T, Conn, B, V>(
+            &self,
+            datastore: newrelic::Datastore,
+            table: &str,
+            query: T,
+            conn: Conn,
+        ) -> (Conn, QueryResult<Vec<V>>)
+        where
+            T: LoadQuery<Conn, V> + QueryFragment<B> + Send + 'static,
+            Conn: Send + 'static,
+            B: Backend,
+            B::QueryBuilder: Default,
+            V: Send + 'static,
+        {
+            let sql = debug_query(&query).to_string();
+            let operation = Operation::from_sql(&sql);
+            self.datastore_segment_async(datastore, table, operation, &sql, || async move {
+                spawn_blocking(move || {
+                    let results = query.load(&conn);
+                    (conn, results)
+                })
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+            })
+            .await
+        }
+
+        /// Execute a Diesel query in a datastore segment, returning the first
+        /// row, with the datastore inferred from the connection's backend.
+        ///
+        /// See `Transaction::diesel_segment_first` for more details.
+        ///
+        /// *Note*: requires one of the `diesel_mysql`, `diesel_postgres` or
+        /// `diesel_sqlite` features.
+        pub fn diesel_first<T, Conn, V>(&self, table: &str, query: T, conn: &Conn) -> QueryResult<V>
+        where
+            T: LimitDsl + QueryFragment<Conn::Backend> + RunQueryDsl<Conn>,
+            Conn: Connection,
+            Conn::Backend: DieselDatastore,
+            <Conn::Backend as Backend>::QueryBuilder: Default,
+            Limit<T>: LoadQuery<Conn, V>,
+        {
+            self.diesel_segment_first::<T, Conn, Conn::Backend, V>(
+                Conn::Backend::datastore(),
+                table,
+                query,
+                conn,
+            )
+        }
+
+        /// Execute a Diesel query in a datastore segment, loading the results,
+        /// with the datastore inferred from the connection's backend.
+        ///
+        /// See `Transaction::diesel_segment_load` for more details.
+        ///
+        /// *Note*: requires one of the `diesel_mysql`, `diesel_postgres` or
+        /// `diesel_sqlite` features.
+        pub fn diesel_load<T, Conn, V>(
+            &self,
+            table: &str,
+            query: T,
+            conn: &Conn,
+        ) -> QueryResult<Vec<V>>
+        where
+            T: LoadQuery<Conn, V> + QueryFragment<Conn::Backend>,
+            Conn: Connection,
+            Conn::Backend: DieselDatastore,
+            <Conn::Backend as Backend>::QueryBuilder: Default,
+        {
+            self.diesel_segment_load::<T, Conn, Conn::Backend, V>(
+                Conn::Backend::datastore(),
+                table,
+                query,
+                conn,
+            )
+        }
+    }
+
+    /// A Diesel backend which corresponds to a New Relic datastore.
+    ///
+    /// This is used by `Transaction::diesel_first` and
+    /// `Transaction::diesel_load` to infer the datastore from the connection.
+    /// Implementations are provided for the Diesel backends enabled using the
+    /// `diesel_mysql`, `diesel_postgres` and `diesel_sqlite` features.
+    ///
+    /// *Note*: requires the `diesel` feature.
+    pub trait DieselDatastore: Backend {
+        /// The New Relic datastore used for queries on this backend.
+        fn datastore() -> newrelic::Datastore;
+    }
+
+    #[cfg(feature = "diesel_mysql")]
+    impl DieselDatastore for diesel::mysql::Mysql {
+        fn datastore() -> newrelic::Datastore {
+            newrelic::Datastore::MySQL
+        }
+    }
+
+    #[cfg(feature = "diesel_postgres")]
+    impl DieselDatastore for diesel::pg::Pg {
+        fn datastore() -> newrelic::Datastore {
+            newrelic::Datastore::Postgres
+        }
+    }
+
+    #[cfg(feature = "diesel_sqlite")]
+    impl DieselDatastore for diesel::sqlite::Sqlite {
+        fn datastore() -> newrelic::Datastore {
+            newrelic::Datastore::SQLite
+        }
+    }
+
+    #[cfg(all(test, feature = "diesel_sqlite"))]
+    mod tests {
+        use diesel::{
+            connection::SimpleConnection, dsl::sql, prelude::*, sql_types::Text, sqlite::Sqlite,
+        };
+        use newrelic::Datastore;
+        use rocket::local::blocking::Client;
+
+        use crate::{NewRelic, RecordedSegment, Transaction};
+
+        /// Open an in-memory database with a `users` table.
+        fn connection() -> SqliteConnection {
+            let conn = SqliteConnection::establish(":memory:").expect("in-memory database");
+            conn.batch_execute(
+                "CREATE TABLE users (name TEXT NOT NULL);
+                 INSERT INTO users (name) VALUES ('alice'), ('bob');",
+            )
+            .expect("users table");
+            conn
+        }
+
+        fn load_names(transaction: &Transaction) -> QueryResult<Vec<String>> {
+            let query = diesel::select(sql::<Text>("name FROM users ORDER BY name"));
+            transaction.diesel_segment_load::<_, _, Sqlite, String>(
+                Datastore::SQLite,
+                "users",
+                query,
+                &connection(),
+            )
+        }
+
+        #[rocket::get("/")]
+        fn names(transaction: &Transaction) -> String {
+            load_names(transaction).expect("query succeeds").join(",")
+        }
+
+        #[test]
+        fn load_records_a_datastore_segment() {
+            let (newrelic, recorder) = NewRelic::builder().build_mock();
+            let rocket = rocket::build()
+                .attach(newrelic)
+                .mount("/", rocket::routes![names]);
+            let client = Client::tracked(rocket).expect("valid rocket");
+            let response = client.get("/").dispatch();
+            assert_eq!(response.into_string().as_deref(), Some("alice,bob"));
+
+            let transactions = recorder.transactions();
+            assert_eq!(transactions.len(), 1);
+            match transactions[0].segments.as_slice() {
+                [RecordedSegment::Datastore {
+                    product,
+                    table,
+                    operation,
+                    sql,
+                }] => {
+                    assert_eq!(*product, "SQLite");
+                    assert_eq!(table, "users");
+                    assert_eq!(operation, "select");
+                    assert!(sql.contains("FROM users"), "unexpected SQL: {}", sql);
+                }
+                segments => panic!("expected one datastore segment, got {:?}", segments),
+            }
+        }
+
+        #[test]
+        fn load_without_a_transaction_still_runs_the_query() {
+            let names = load_names(&Transaction::None).expect("query succeeds");
+            assert_eq!(names, ["alice", "bob"]);
+        }
+    }
+}
+
+#[cfg(feature = "diesel-async")]
+mod diesel_async {
+    use diesel::{backend::Backend, query_builder::QueryFragment, QueryResult};
+    use diesel_async::{methods::LoadQuery, AsyncConnection, RunQueryDsl};
+    use diesel_async_diesel as diesel;
+
+    use super::{Operation, Transaction};
+
+    impl Transaction {
+        /// Execute a Diesel query on a `diesel-async` connection in a
+        /// datastore segment, loading the results.
+        ///
+        /// The segment is kept open until the query has completed. See
+        /// `Transaction::datastore_segment` for more details.
+        ///
+        /// *Note*: requires the `diesel-async` feature.
+        pub async fn diesel_segment_load_async<'query, 'conn, T, Conn, V>(
+            &self,
+            datastore: newrelic::Datastore,
+            table: &str,
+            query: T,
+            conn: &'conn mut Conn,
+        ) -> QueryResult<Vec<V>>
+        where
+            T: LoadQuery<'query, Conn, V> + QueryFragment<Conn::Backend> + 'query,
+            Conn: AsyncConnection,
+            Conn::Backend: Default,
+            <Conn::Backend as Backend>::QueryBuilder: Default,
+            V: Send,
+        {
+            let sql = diesel::debug_query::<Conn::Backend, _>(&query).to_string();
+            let operation = Operation::from_sql(&sql);
+            self.datastore_segment_async(datastore, table, operation, &sql, || query.load(conn))
+                .await
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod tracing {
+    use tracing::Span;
+
+    /// Attributes correlating a transaction with the current `tracing` span,
+    /// if there is one.
+    pub fn span_attributes() -> Option<(String, String)> {
+        Span::current()
+            .id()
+            .map(|id| ("trace.span_id".to_string(), id.into_u64().to_string()))
+    }
+}
+
+#[cfg(feature = "sqlx")]
+mod sqlx {
+    use std::future::Future;
+
+    use sqlx::{Database, Execute};
+
+    use super::{Operation, Transaction};
+
+    impl Transaction {
+        /// Execute an SQLx query in a datastore segment.
+        ///
+        /// The query's SQL is recorded in the segment, and `query` is then
+        /// passed to `func`, which should execute it, e.g.
+        /// `|q| q.fetch_all(&pool)`. The segment is kept open until the
+        /// future returned by `func` has completed.
+        ///
+        /// See `Transaction::datastore_segment` for details of the other
+        /// arguments.
+        ///
+        /// *Note*: requires the `sqlx` feature.
+        pub async fn sqlx_segment<'q, DB, Q, O, F, Fut, V>(
+            &self,
+            datastore: newrelic::Datastore,
+            table: &str,
+            operation: O,
+            query: Q,
+            func: F,
+        ) -> V
+        where
+            DB: Database,
+            O: Into<Operation>,
+            Q: Execute<'q, DB>,
+            F: FnOnce(Q) -> Fut,
+            Fut: Future<Output = V>,
+        {
+            let sql = query.sql();
+            self.datastore_segment_async(datastore, table, operation, sql, || func(query))
+                .await
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde {
+    use log::warn;
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use super::Transaction;
+
+    impl Transaction {
+        /// Add the top-level fields of a serializable struct (or map) as
+        /// attributes of the transaction.
+        ///
+        /// Strings and numbers are added as they are, and booleans are added
+        /// as `"true"` or `"false"`. Null, nested object and array fields are
+        /// skipped. Redacted keys are handled in the same way as
+        /// `add_attribute`.
+        ///
+        /// *Note*: requires the `serde` feature.
+        pub fn add_serialized<T: Serialize>(&self, value: &T) {
+            let fields = match serde_json::to_value(value) {
+                Ok(Value::Object(fields)) => fields,
+                Ok(_) => {
+                    warn!("Could not add serialized attributes: value isn't a struct or map");
+                    return;
+                }
+                Err(e) => {
+                    warn!("Could not serialize attributes: {}", e);
+                    return;
+                }
+            };
+            let attributes = fields.iter().filter_map(|(key, value)| {
+                let attribute = match value {
+                    Value::Bool(b) => {
+                        newrelic::Attribute::String(if *b { "true" } else { "false" })
+                    }
+                    Value::Number(n) => match n.as_i64() {
+                        Some(i) => newrelic::Attribute::Long(i),
+                        None => newrelic::Attribute::Float(n.as_f64()?),
+                    },
+                    Value::String(s) => newrelic::Attribute::OwnedString(s),
+                    Value::Null | Value::Array(_) | Value::Object(_) => return None,
+                };
+                Some((key.as_str(), attribute))
+            });
+            self.add_attributes(attributes);
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+mod reqwest {
+    use log::warn;
+    use reqwest::{Client, Request, Response};
+
+    use super::Transaction;
+
+    impl Transaction {
+        /// Execute a `reqwest` request in an external segment.
+        ///
+        /// The segment's host is the origin of the request's URL (e.g.
+        /// `https://api.example.com`), its procedure is the request method
+        /// and its library is `reqwest`. The segment is kept open until the
+        /// response headers have been received.
+        ///
+        /// ```rust
+        /// # use rocket_newrelic::Transaction;
+        /// # async fn call(transaction: &Transaction) -> reqwest::Result<()> {
+        /// let client = reqwest::Client::new();
+        /// let request = client.get("https://api.example.com/users").build()?;
+        /// let response = transaction.reqwest_segment(&client, request).await?;
+        /// # Ok(())
+        /// # }
+        /// ```
+        ///
+        /// With the `distributed_tracing` feature enabled, the distributed
+        /// trace headers for the segment are added to the request (see
+        /// `distributed_trace_headers`).
+        ///
+        /// *Note*: requires the `reqwest` feature.
+        pub async fn reqwest_segment(
+            &self,
+            client: &Client,
+            request: Request,
+        ) -> reqwest::Result<Response> {
+            let _open = match self.enter_segment() {
+                Some(open) => open,
+                None => return client.execute(request).await,
+            };
+            let host = request.url().origin().ascii_serialization();
+            let procedure = request.method().to_string();
             match self {
-                Transaction::Running(inner) => match inner.0.read() {
-                    Ok(t) => {
-                        let t = inner.0.read().expect("Mutex problem");
-                        let sql = debug_query(&query).to_string();
-                        let params = newrelic::DatastoreParamsBuilder::new(datastore)
-                            .collection(table)
-                            .operation("select")
-                            .query(&sql)
-                            .build();
-                        match params {
-                            Ok(p) => t.datastore_segment(&p, |_| query.load(conn)),
-                            Err(e) => {
-                                warn!("Error building New Relic datastore parameters: {}", e);
-                                query.load(conn)
-                            }
+                Transaction::Running(inner) => {
+                    let t = match inner.read().await {
+                        Some(t) => t,
+                        None => return client.execute(request).await,
+                    };
+                    let procedure = inner.config.segment_name(&procedure);
+                    let params = newrelic::ExternalParamsBuilder::new(&host)
+                        .procedure(&procedure)
+                        .library("reqwest")
+                        .build();
+                    match params {
+                        Ok(p) => {
+                            let segment = t.create_external_segment(&p);
+                            let request = add_trace_headers(&segment, request);
+                            client.execute(request).await
+                        }
+                        Err(e) => {
+                            warn!("Error building external New Relic parameters: {}", e);
+                            client.execute(request).await
                         }
                     }
-                    Err(e) => {
-                        warn!("Error locking transaction RwLock: {}", e);
-                        query.load(conn)
-                    }
-                },
-                Transaction::None => query.load(conn),
+                }
+                Transaction::Mock(mock) => {
+                    mock.external_segment(&host, Some(&procedure), Some("reqwest"));
+                    client.execute(request).await
+                }
+                Transaction::None => client.execute(request).await,
+            }
+        }
+    }
+
+    /// Add the distributed trace headers for `segment` to the request.
+    #[cfg(feature = "distributed_tracing")]
+    fn add_trace_headers(segment: &newrelic::Segment, mut request: Request) -> Request {
+        use reqwest::header::{HeaderName, HeaderValue};
+
+        for (name, value) in super::distributed_trace_headers(segment) {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    request.headers_mut().insert(name, value);
+                }
+                _ => warn!("Invalid distributed trace header {}", name),
             }
         }
+        request
+    }
+
+    #[cfg(not(feature = "distributed_tracing"))]
+    fn add_trace_headers(_segment: &newrelic::Segment, request: Request) -> Request {
+        request
+    }
+}
+
+#[cfg(feature = "distributed_tracing")]
+pub use distributed_tracing::distributed_trace_headers;
+
+#[cfg(feature = "distributed_tracing")]
+mod distributed_tracing {
+    use std::collections::HashMap;
+
+    /// The header used by New Relic agents to propagate distributed traces.
+    const NEWRELIC_HEADER: &str = "newrelic";
+
+    /// Create the headers needed to propagate the distributed trace to an
+    /// external service called from within `segment`.
+    ///
+    /// The headers should be added to the outgoing request, for example:
+    ///
+    /// ```rust
+    /// # use rocket_newrelic::Transaction;
+    /// # async fn call(transaction: &Transaction) {
+    /// let url = "https://logging-thing";
+    /// let request = transaction.external_segment(url, Some("get"), Some("reqwest"), |s| {
+    ///     let mut request = reqwest::Client::new().get(url);
+    ///     for (name, value) in rocket_newrelic::distributed_trace_headers(&s) {
+    ///         request = request.header(name, value);
+    ///     }
+    ///     request
+    /// });
+    /// # }
+    /// ```
+    ///
+    /// The New Relic SDK only creates New Relic format payloads, so W3C
+    /// `traceparent` and `tracestate` headers aren't included. The map is
+    /// empty if distributed tracing is disabled or the segment isn't running.
+    ///
+    /// *Note*: requires the `distributed_tracing` feature.
+    pub fn distributed_trace_headers(segment: &newrelic::Segment) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        let payload = segment.distributed_trace();
+        if !payload.is_empty() {
+            headers.insert(NEWRELIC_HEADER.to_string(), payload);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[rocket::get("/")]
+    fn index(_transaction: &Transaction) -> &'static str {
+        "Hello"
+    }
+
+    #[rocket::get("/pending")]
+    async fn pending(_transaction: &Transaction) {
+        std::future::pending::<()>().await;
+    }
+
+    #[rocket::get("/letters")]
+    fn letters(transaction: &Transaction) {
+        transaction.add_attribute("letters", "abcdefgh");
+    }
+
+    #[rocket::get("/unguarded")]
+    fn unguarded() {}
+
+    #[rocket::get("/siblings")]
+    fn siblings(transaction: &Transaction) {
+        let _handles: Vec<_> = (0..3)
+            .map(|_| transaction.start_custom_segment("sibling", "test"))
+            .collect();
+    }
+
+    /// A route whose handler has an empty name.
+    fn empty_named_route() -> Route {
+        fn handler<'r>(request: &'r Request<'_>, _: Data<'r>) -> rocket::route::BoxFuture<'r> {
+            Box::pin(async move {
+                let _ = request.guard::<&Transaction>().await;
+                rocket::route::Outcome::from(request, ())
+            })
+        }
+        let mut route = Route::new(Method::Get, "/empty", handler);
+        route.name = Some("".into());
+        route
+    }
+
+    /// Send `count` requests to `path` through a mock fairing, returning the
+    /// transactions it recorded.
+    fn record(
+        (newrelic, recorder): (NewRelic, MockRecorder),
+        routes: Vec<Route>,
+        path: &str,
+        count: usize,
+    ) -> Vec<RecordedTransaction> {
+        let rocket = rocket::build().attach(newrelic).mount("/", routes);
+        let client = Client::tracked(rocket).expect("valid rocket");
+        for _ in 0..count {
+            client.get(path).dispatch();
+        }
+        recorder.transactions()
+    }
+
+    #[rocket::async_test]
+    async fn dropped_requests_are_recorded_without_a_response() {
+        let (newrelic, recorder) = NewRelic::mock();
+        let rocket = rocket::build()
+            .attach(newrelic)
+            .mount("/", rocket::routes![pending]);
+        let client = rocket::local::asynchronous::Client::tracked(rocket)
+            .await
+            .expect("valid rocket");
+        // Run the request as far as the handler, then give up on it.
+        let mut dispatch = Box::pin(client.get("/pending").dispatch());
+        let poll = std::future::poll_fn(|cx| Poll::Ready(dispatch.as_mut().poll(cx))).await;
+        assert!(poll.is_pending());
+        drop(dispatch);
+
+        let transactions = recorder.transactions();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].attribute("response.status_code"), None);
+    }
+
+    /// The `letters` attribute recorded with a maximum attribute length of
+    /// `length`.
+    fn truncated_letters(length: usize) -> Option<RecordedAttribute> {
+        let mock = NewRelic::builder()
+            .max_attribute_length(length)
+            .build_mock();
+        let transactions = record(mock, rocket::routes![letters], "/letters", 1);
+        transactions[0].attribute("letters").cloned()
+    }
+
+    #[test]
+    fn max_attribute_length_truncates_long_values() {
+        for (length, expected) in [(7, "abcd..."), (4, "a..."), (3, "...")] {
+            assert_eq!(
+                truncated_letters(length),
+                Some(RecordedAttribute::String(expected.to_string())),
+                "length {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn max_attribute_length_shorter_than_the_ellipsis_cuts_values_short() {
+        for (length, expected) in [(2, "ab"), (1, "a"), (0, "")] {
+            assert_eq!(
+                truncated_letters(length),
+                Some(RecordedAttribute::String(expected.to_string())),
+                "length {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn max_attribute_length_leaves_short_values() {
+        for length in [8, 100] {
+            assert_eq!(
+                truncated_letters(length),
+                Some(RecordedAttribute::String("abcdefgh".to_string())),
+                "length {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn empty_route_names_fall_back_to_the_base_or_unknown_handler() {
+        let (newrelic, recorder) = NewRelic::mock();
+        let rocket = rocket::build()
+            .attach(newrelic)
+            .mount("/", vec![empty_named_route()])
+            .mount("/api/", vec![empty_named_route()]);
+        let client = Client::tracked(rocket).expect("valid rocket");
+        client.get("/empty").dispatch();
+        client.get("/api/empty").dispatch();
+
+        let names: Vec<_> = recorder
+            .transactions()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, ["unknown_handler", "api"]);
+    }
+
+    #[test]
+    fn queue_time_is_measured_until_the_handler_starts() {
+        let transactions = record(NewRelic::mock(), rocket::routes![index], "/", 1);
+        assert!(matches!(
+            transactions[0].attribute("request.queue_time_ms"),
+            Some(RecordedAttribute::Long(_))
+        ));
+    }
+
+    #[test]
+    fn queue_time_is_measured_from_the_request_start_header() {
+        let (newrelic, recorder) = NewRelic::mock();
+        let rocket = rocket::build()
+            .attach(newrelic)
+            .mount("/", rocket::routes![index]);
+        let client = Client::tracked(rocket).expect("valid rocket");
+        let start = SystemTime::now() - Duration::from_secs(5);
+        let start_ms = start.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        client
+            .get("/")
+            .header(rocket::http::Header::new(
+                "X-Request-Start",
+                format!("t={}", start_ms),
+            ))
+            .dispatch();
+
+        match recorder.transactions()[0].attribute("request.queue_time_ms") {
+            Some(RecordedAttribute::Long(ms)) => assert!((5000..60_000).contains(ms), "{}", ms),
+            other => panic!("expected a queue time, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn queue_time_is_skipped_if_the_handler_start_is_unknown() {
+        let mock = NewRelic::builder().instrument_all(true).build_mock();
+        let transactions = record(mock, rocket::routes![unguarded], "/unguarded", 1);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].attribute("request.queue_time_ms"), None);
+    }
+
+    #[test]
+    fn max_open_segments_counts_sibling_segments() {
+        let mock = NewRelic::builder().max_open_segments(2).build_mock();
+        let transactions = record(mock, rocket::routes![siblings], "/siblings", 1);
+        assert_eq!(transactions[0].segments.len(), 2);
+    }
+
+    #[test]
+    fn sample_rate_of_zero_instruments_no_requests() {
+        let mock = NewRelic::builder().sample_rate(0.0).build_mock();
+        assert!(record(mock, rocket::routes![index], "/", 10).is_empty());
+    }
+
+    #[test]
+    fn sample_rate_of_one_instruments_every_request() {
+        let mock = NewRelic::builder().sample_rate(1.0).build_mock();
+        assert_eq!(record(mock, rocket::routes![index], "/", 10).len(), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample rate must be between 0.0 and 1.0")]
+    fn sample_rate_above_one_is_rejected() {
+        let _ = NewRelic::builder().sample_rate(1.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample rate must be between 0.0 and 1.0")]
+    fn negative_sample_rate_is_rejected() {
+        let _ = NewRelic::builder().sample_rate(-0.1);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample rate must be between 0.0 and 1.0")]
+    fn nan_sample_rate_is_rejected() {
+        let _ = NewRelic::builder().sample_rate(f64::NAN);
     }
 }